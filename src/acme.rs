@@ -0,0 +1,221 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime}};
+
+use axum::{BoxError, extract::Path as UrlPath, Extension, http::StatusCode};
+use axum_server::tls_rustls::RustlsConfig;
+use instant_acme::{Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, OrderStatus};
+use rcgen::{Certificate, CertificateParams, DistinguishedName};
+use tokio::sync::RwLock;
+
+use crate::config::{AcmeConfig, AppConfig};
+
+/// Pending HTTP-01 challenges, token to key authorization, served on the HTTP listener.
+pub type Challenges = Arc<RwLock<HashMap<String, String>>>;
+
+/// Path prefix the ACME server requests HTTP-01 challenges on.
+pub const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Order status checks before giving up, with the delay doubling from 250ms in between.
+const ORDER_POLLS: u32 = 10;
+
+/// Checks for the issued certificate after finalizing, one second apart.
+const CERTIFICATE_POLLS: u32 = 30;
+
+/// Upper bound on waiting for the issued certificate, slow requests included.
+const CERTIFICATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Acme {
+    config: AcmeConfig,
+    cert: PathBuf,
+    key: PathBuf,
+    challenges: Challenges,
+}
+
+impl Acme {
+    #[must_use]
+    pub fn new(config: &AppConfig, challenges: Challenges) -> Self {
+        Self {
+            config: config.acme.clone(),
+            cert: config.tls.cert.clone(),
+            key: config.tls.key.clone(),
+            challenges,
+        }
+    }
+
+    /// Obtain a certificate if there is none on disk or the current one is due for renewal.
+    /// Returns whether a new certificate was written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the ACME server rejects the account or order, the challenges fail
+    /// or the certificate cannot be stored.
+    pub async fn ensure_certificate(&self) -> Result<bool, BoxError> {
+        if !self.needs_renewal() {
+            return Ok(false);
+        }
+
+        tracing::info!(domains = ?self.config.domains, directory = %self.config.directory_url, "ACME: requesting certificate");
+
+        let (cert, key) = self.order().await?;
+
+        write_atomically(&self.key, key.as_bytes())?;
+        write_atomically(&self.cert, cert.as_bytes())?;
+
+        tracing::info!(path = %self.cert.display(), "ACME: certificate stored");
+
+        Ok(true)
+    }
+
+    /// Periodically renew the certificate and hot swap it into `rustls`.
+    pub async fn renew(self, rustls: RustlsConfig) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.check_interval_secs.max(60)));
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            match self.ensure_certificate().await {
+                Ok(true) => match rustls.reload_from_pem_file(&self.cert, &self.key).await {
+                    Ok(()) => tracing::info!("ACME: renewed certificate loaded"),
+                    Err(e) => tracing::error!(error = ?e, "ACME: failed to load renewed certificate"),
+                },
+                Ok(false) => {}
+                Err(e) => tracing::warn!(error = ?e, "ACME: renewal failed, will retry"),
+            }
+        }
+    }
+
+    fn needs_renewal(&self) -> bool {
+        let age = std::fs::metadata(&self.cert)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+
+        match age {
+            Some(age) => age >= Duration::from_secs(self.config.renew_after_days * 24 * 60 * 60) || !self.key.is_file(),
+            None => true,
+        }
+    }
+
+    async fn account(&self) -> Result<Account, BoxError> {
+        if let Ok(credentials) = std::fs::read_to_string(&self.config.account_file) {
+            let credentials: AccountCredentials = serde_json::from_str(&credentials)?;
+            return Ok(Account::from_credentials(credentials).await?);
+        }
+
+        let contact = self.config.contact.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let (account, credentials) = Account::create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            &self.config.directory_url,
+            None,
+        )
+        .await?;
+
+        write_atomically(&self.config.account_file, serde_json::to_string_pretty(&credentials)?.as_bytes())?;
+
+        Ok(account)
+    }
+
+    /// Run a full order and return the PEM certificate chain and private key.
+    async fn order(&self) -> Result<(String, String), BoxError> {
+        let account = self.account().await?;
+
+        let identifiers = self.config.domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect::<Vec<_>>();
+
+        let mut order = account.new_order(&NewOrder { identifiers: &identifiers }).await?;
+
+        let mut ready = Vec::new();
+        for authz in order.authorizations().await? {
+            match authz.status {
+                AuthorizationStatus::Valid => continue,
+                AuthorizationStatus::Pending => {}
+                status => return Err(format!("authorization for {:?} is {status:?}", authz.identifier).into()),
+            }
+
+            let challenge = authz.challenges
+                .iter()
+                .find(|c| c.r#type == ChallengeType::Http01)
+                .ok_or("ACME server offered no HTTP-01 challenge")?;
+
+            let key_authorization = order.key_authorization(challenge);
+            self.challenges.write().await.insert(challenge.token.clone(), key_authorization.as_str().to_string());
+            ready.push((challenge.token.clone(), challenge.url.clone()));
+        }
+
+        let result = self.finish(&mut order, &ready).await;
+
+        let mut challenges = self.challenges.write().await;
+        for (token, _) in &ready {
+            challenges.remove(token);
+        }
+
+        result
+    }
+
+    async fn finish(&self, order: &mut instant_acme::Order, ready: &[(String, String)]) -> Result<(String, String), BoxError> {
+        for (_, url) in ready {
+            order.set_challenge_ready(url).await?;
+        }
+
+        let mut delay = Duration::from_millis(250);
+        let mut status = OrderStatus::Pending;
+        for _ in 0..ORDER_POLLS {
+            tokio::time::sleep(delay).await;
+
+            status = order.refresh().await?.status;
+            match status {
+                OrderStatus::Ready => break,
+                OrderStatus::Invalid => return Err("ACME order became invalid; check that port 80 is reachable".into()),
+                _ => delay *= 2,
+            }
+        }
+
+        if status != OrderStatus::Ready {
+            return Err(format!("ACME order is still {status:?} after {ORDER_POLLS} checks").into());
+        }
+
+        let mut params = CertificateParams::new(self.config.domains.clone());
+        params.distinguished_name = DistinguishedName::new();
+        let certificate = Certificate::from_params(params)?;
+
+        order.finalize(&certificate.serialize_request_der()?).await?;
+
+        let chain = tokio::time::timeout(CERTIFICATE_TIMEOUT, async {
+            for _ in 0..CERTIFICATE_POLLS {
+                if let Some(chain) = order.certificate().await? {
+                    return Ok(chain);
+                }
+
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+
+            Err::<_, BoxError>(format!("ACME server issued no certificate after {CERTIFICATE_POLLS} checks").into())
+        })
+        .await
+        .map_err(|_| format!("ACME server issued no certificate within {CERTIFICATE_TIMEOUT:?}"))??;
+
+        Ok((chain, certificate.serialize_private_key_pem()))
+    }
+}
+
+/// Answer an HTTP-01 challenge request with its key authorization.
+pub async fn serve_challenge(Extension(challenges): Extension<Challenges>, UrlPath(token): UrlPath<String>) -> Result<String, StatusCode> {
+    challenges.read().await.get(&token).cloned().ok_or(StatusCode::NOT_FOUND)
+}
+
+fn write_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(tmp, path)
+}
@@ -1,60 +1,197 @@
-use axum::{extract::FromRequestParts, async_trait, RequestPartsExt, http::request::Parts};
-use axum_extra::extract::PrivateCookieJar;
-use surrealdb::sql::Thing;
-use crate::pool::SurrealConnection;
-use crate::state::Context;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::{extract::FromRequestParts, async_trait, http::request::Parts};
+use axum_extra::extract::cookie::Cookie;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use surrealdb::sql::{Datetime, Thing};
+use tower_http::request_id::RequestId;
+use crate::config::AppConfig;
+use crate::pool::{Privileged, SurrealConnection};
+use crate::state::{Context, State};
 use crate::error::Error;
+use crate::{monitoring, retry};
+
+/// Token definition on the account scope that SurrealDB checks minted session tokens with.
+const TOKEN_NAME: &str = "session";
+/// Lifetime of session tokens, the same as `SESSION 24h` on the `account` scope.
+pub const SESSION: Duration = Duration::from_secs(24 * 60 * 60);
 
 #[derive(Debug, Clone, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Session {
     #[serde(skip)]
     token: String,
+    /// Key of the server-side session record this token belongs to.
+    #[serde(skip)]
+    session: String,
     id: Thing,
     #[serde(default)]
     is_admin: bool,
+    #[serde(default)]
+    disabled: bool,
+    #[serde(default)]
+    verified: bool,
+    #[serde(default)]
+    totp_enabled: bool,
+    /// Unverified account while `auth.restrict_unverified` is on: may browse, not upload.
+    #[serde(skip)]
+    restricted: bool,
+    /// Tokens issued before this instant predate a password reset and are rejected.
+    #[serde(default)]
+    pass_changed_at: Option<Datetime>,
+    /// When the server-side session was opened, which caps how long it is renewed for.
+    #[serde(skip)]
+    opened_at: Option<Datetime>,
     first_name: String,
     last_name: String,
     email: String,
 }
 
 impl Session {
-    /// Create a new `Session` from a token and a database connection.
+    /// Create a new `Session` from a token, the server-side session it was issued with and a
+    /// database connection. `request_id` ties the lookup to the request that triggered it in
+    /// the logs.
     /// 
     /// # Errors
     ///
-    /// This function will return an error if the token is invalid or the database is unreachable.
-    pub async fn new(token: String, db: SurrealConnection) -> Result<Session, Error> {
-        db.authenticate(&token).await?;
-        
-        let mut res = db.query("SELECT * FROM $auth.id").await?;
- 
-        let user: Result<Option<Session>, _> = res.take(0);
+    /// This function will return an error if the token is invalid, the session was revoked or
+    /// the database is unreachable.
+    #[tracing::instrument(name = "session", skip_all, fields(request_id = request_id, user = tracing::field::Empty))]
+    pub async fn new(token: String, session: String, db: SurrealConnection, request_id: &str) -> Result<Session, Error> {
+        let user = Self::lookup(&token, &session, &db).await;
+
+        if let Err(e) = &user {
+            crate::pool::discard_if_broken(db, e);
+        }
+
         match user {
-            Ok(Some(mut user)) => {
+            Ok((Some(user), None)) => {
+                tracing::warn!(user = %user.id, "Auth error: session was revoked");
+                Err(Error::AuthFailed("session was revoked".to_string()))
+            },
+            Ok((Some(user), _)) if user.disabled => {
+                tracing::warn!(user = %user.id, "Auth error: account is disabled");
+                Err(Error::AuthFailed("account is disabled".to_string()))
+            },
+            Ok((Some(user), _)) if user.predates_password_change(&token) => {
+                tracing::warn!(user = %user.id, "Auth error: token issued before the last password change");
+                Err(Error::AuthFailed("token issued before the last password change".to_string()))
+            },
+            Ok((Some(mut user), opened_at)) => {
+                tracing::Span::current().record("user", tracing::field::display(&user.id));
                 user.token = token;
+                user.session = session;
+                user.opened_at = opened_at;
                 Ok(user)
             },
-            Ok(None) => {
-                println!("Auth error: {e:?}", e = Error::AuthFailed);
-                Err(Error::AuthFailed)
+            Ok((None, _)) => {
+                tracing::warn!("Auth error: no user record for token");
+                Err(Error::AuthFailed("no user record for token".to_string()))
             },
+            Err(e) if e.is_transient() => Err(e),
             Err(e) => {
-                println!("Auth error: {e:?}");
-                Err(Error::AuthFailed)
+                tracing::warn!(error = ?e, "Auth error");
+                Err(Error::AuthFailed(e.to_string()))
             }, 
         }  
     }
 
+    /// The user of `token`, and when `session` was opened if it is still open. Seeing the
+    /// session also moves its last seen time forward, at most once a minute.
+    async fn lookup(token: &str, session: &str, db: &SurrealConnection) -> Result<(Option<Session>, Option<Datetime>), Error> {
+        db.authenticate(token).await.map_err(|e| {
+            if crate::error::is_connection_error(&e) {
+                Error::Database(e)
+            } else {
+                Error::AuthFailed(e.to_string())
+            }
+        })?;
+
+        let mut res = db.query("
+            SELECT * FROM $auth.id;
+            SELECT VALUE created_at FROM type::thing('session', $session) WHERE user = $auth.id;
+            UPDATE type::thing('session', $session) SET last_seen_at = time::now()
+                WHERE user = $auth.id AND last_seen_at < time::now() - 1m
+                RETURN NONE;
+        ")
+            .bind(("session", session))
+            .await?;
+
+        let user = res.take(0)?;
+        let opened_at = res.take(1)?;
+
+        Ok((user, opened_at))
+    }
+
+    /// Tokens carry whole seconds, so one issued in the second of the change is accepted:
+    /// signing in right after a reset must work, and the reset closes older sessions anyway.
+    fn predates_password_change(&self, token: &str) -> bool {
+        match (&self.pass_changed_at, Claims::decode(token)) {
+            (Some(changed), Some(claims)) => claims.iat < changed.timestamp(),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// A fresh token for this session if the current one expires within the renewal window.
+    /// The new token never outlives the maximum session age, so a session past it is left
+    /// to expire.
+    fn renewal(&self, state: &State) -> Option<String> {
+        let claims = Claims::decode(&self.token)?;
+        let now = i64::try_from(unix_now().as_secs()).ok()?;
+
+        if state.token_secret.is_none() || claims.exp - now > i64::try_from(state.renew_within.as_secs()).ok()? {
+            return None;
+        }
+
+        let max_age = i64::try_from(state.max_session_age.as_secs()).ok()?;
+        let session = i64::try_from(SESSION.as_secs()).ok()?;
+        let exp = (now + session).min(self.opened_at.as_ref()?.timestamp() + max_age);
+
+        if exp <= claims.exp {
+            return None;
+        }
+
+        match mint(state, &self.id, Duration::from_secs(u64::try_from(exp - now).ok()?)) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                tracing::warn!(error = ?e, user = %self.id, "Could not renew the session token");
+                None
+            },
+        }
+    }
+
     #[must_use]
     pub fn token(&self) -> &str {
         &self.token
     }
 
+    /// Key of the server-side session, as listed on the sessions page.
+    #[must_use]
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
     #[must_use]
     pub fn is_admin(&self) -> bool {
         self.is_admin
     }
 
+    #[must_use]
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    #[must_use]
+    pub fn is_restricted(&self) -> bool {
+        self.restricted
+    }
+
+    #[must_use]
+    pub fn has_two_factor(&self) -> bool {
+        self.totp_enabled
+    }
+
     #[must_use]
     pub fn id(&self) -> &Thing {
         &self.id
@@ -76,21 +213,157 @@ impl Session {
     }
 }
 
+fn unix_now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Claims SurrealDB expects in a token for a record of a scope.
+#[derive(Debug, serde::Serialize)]
+struct MintedClaims<'a> {
+    iss: &'a str,
+    iat: u64,
+    nbf: u64,
+    exp: u64,
+    #[serde(rename = "NS")]
+    ns: &'a str,
+    #[serde(rename = "DB")]
+    db: &'a str,
+    #[serde(rename = "SC")]
+    sc: &'a str,
+    #[serde(rename = "TK")]
+    tk: &'a str,
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+/// Mint a token for `user` valid for `lifetime` that SurrealDB accepts like one from signing
+/// in to the account scope.
+///
+/// # Errors
+///
+/// Returns `Error::Unavailable` if no token secret is configured or signing fails.
+pub fn mint(state: &State, user: &Thing, lifetime: Duration) -> Result<String, Error> {
+    let secret = state.token_secret.as_deref().ok_or_else(|| Error::Unavailable("auth.token_secret is not set".to_string()))?;
+    let now = unix_now().as_secs();
+
+    let claims = MintedClaims {
+        iss: "SurrealDB",
+        iat: now,
+        nbf: now,
+        exp: now + lifetime.as_secs(),
+        ns: &state.namespace,
+        db: &state.database,
+        sc: &state.scope,
+        tk: TOKEN_NAME,
+        id: user.to_string(),
+    };
+
+    jsonwebtoken::encode(&Header::new(Algorithm::HS512), &claims, &EncodingKey::from_secret(secret.as_bytes()))
+        .map_err(|e| Error::Unavailable(format!("cannot sign session token: {e}")))
+}
+
+/// Define the key SurrealDB checks minted session tokens with, when `auth.token_secret` is
+/// set. Runs at startup, as root.
+///
+/// # Errors
+///
+/// Returns an error if the database cannot be reached or the definition fails.
+pub async fn install(root: &Privileged, config: &AppConfig) -> Result<(), Error> {
+    let Some(secret) = &config.auth.token_secret else {
+        return Ok(());
+    };
 
+    let db = root.get().await?;
 
+    // DEFINE statements take no parameters; `AppConfig::load` checks both values are plain.
+    db.query(format!("DEFINE TOKEN {TOKEN_NAME} ON SCOPE {} TYPE HS512 VALUE \"{secret}\"", config.surreal.scope))
+        .await?
+        .check()?;
+
+    Ok(())
+}
+
+/// Slot for a session cookie renewed while handling a request. The `Session` extractor fills
+/// it and `middleware::renew_session` sends it with the response.
+#[derive(Debug, Clone, Default)]
+pub struct Renewal(Arc<Mutex<Option<Cookie<'static>>>>);
+
+impl Renewal {
+    fn set(&self, cookie: Cookie<'static>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(cookie);
+    }
+
+    #[must_use]
+    pub fn take(&self) -> Option<Cookie<'static>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).take()
+    }
+}
+
+/// Registered claims of a SurrealDB session token.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub struct Claims {
+    /// Issued at, seconds since the Unix epoch.
+    pub iat: i64,
+    /// Expiry, seconds since the Unix epoch.
+    pub exp: i64,
+}
+
+impl Claims {
+    /// Read the claims of a token without checking its signature; only use this on tokens
+    /// SurrealDB already accepted.
+    #[must_use]
+    pub fn decode(token: &str) -> Option<Self> {
+        let payload = token.split('.').nth(1)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+
+        serde_json::from_slice(&payload).ok()
+    }
+}
 
 #[async_trait]
 impl FromRequestParts<Context> for Session
 {
     type Rejection = Error;
+    /// Resolved once per request, kept in the request extensions for the other extractors,
+    /// and looked up in the session cache before asking the database.
     async fn from_request_parts(parts: &mut Parts, state: &Context) -> Result<Self, Self::Rejection> {
-        let jar = match parts.extract_with_state::<PrivateCookieJar, Context>(state).await {
-            Ok(jar) => jar,
-            Err(e) => unreachable!("Failed to extract cookie jar: {:?}", e),
+        if let Some(session) = parts.extensions.get::<Session>() {
+            return Ok(session.clone());
+        }
+
+        let cookie = state.session_token(&parts.headers).ok_or(Error::AuthNoToken)?;
+        let cached = state.session_cache.get(&cookie.session, &cookie.token);
+        monitoring::record_session_cache(cached.is_some());
+
+        let mut session = if let Some(session) = cached {
+            session
+        } else {
+            let request_id = parts.extensions
+                .get::<RequestId>()
+                .and_then(|id| id.header_value().to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+
+            let session = retry::retry(&state.retry, &state.breaker, || async {
+                Session::new(cookie.token.clone(), cookie.session.clone(), state.surreal.get().await?, &request_id).await
+            }).await?;
+
+            state.session_cache.insert(&session);
+            session
         };
-        
-        let token = jar.get("token").ok_or(Error::AuthNoToken)?.value().to_string();
 
-        Ok(Session::new(token, state.surreal.get().await?).await?)
+        session.restricted = state.restrict_unverified && !session.verified;
+
+        if let Some(renewal) = parts.extensions.get::<Renewal>() {
+            if let Some(token) = session.renewal(state) {
+                tracing::debug!(user = %session.id, "Session token renewed");
+                renewal.set(state.session_cookie(&session.session, &token));
+                session.token = token;
+            }
+        }
+
+        parts.extensions.insert(session.clone());
+
+        Ok(session)
     }
 }
\ No newline at end of file
@@ -0,0 +1,210 @@
+use axum::BoxError;
+use clap::{Parser, Subcommand};
+use surrealdb::{Surreal, engine::any::Any, sql::Thing};
+
+use crate::{config::{AppConfig, Purpose}, error::Error, migrate, pool::Privileged, two_factor};
+
+/// Stickers web server and administration tool.
+///
+/// Every subcommand reads the same configuration file and environment as the server, but
+/// commands that only touch the database validate just the `surreal` section. They sign in
+/// with `surreal.username` and `surreal.password`.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the web server (the default).
+    Serve,
+    /// Apply pending schema migrations.
+    Migrate {
+        /// Only list the migrations that would be applied.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Manage user accounts.
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Print a new random cookie key.
+    GenKey,
+}
+
+impl Command {
+    /// The configuration this command needs; database commands run without TLS files or a
+    /// cookie key.
+    #[must_use]
+    pub fn purpose(&self) -> Purpose {
+        match self {
+            Self::Migrate { .. } | Self::User { .. } => Purpose::Database,
+            Self::Serve | Self::GenKey => Purpose::Serve,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create an account. The password is prompted for unless `STICKERS_PASSWORD` is set.
+    Create {
+        username: String,
+        email: String,
+        first_name: String,
+        last_name: String,
+        /// Make the new account an administrator.
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Grant administrator rights to an account.
+    Promote {
+        username: String,
+        /// Take administrator rights away instead.
+        #[arg(long)]
+        revoke: bool,
+    },
+    /// List every account.
+    List,
+    /// Prevent an account from signing in.
+    Disable {
+        username: String,
+        /// Allow the account to sign in again instead.
+        #[arg(long)]
+        undo: bool,
+    },
+    /// Turn off two-factor authentication for an account that lost its authenticator and
+    /// recovery codes.
+    ResetTwoFactor {
+        username: String,
+    },
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct UserRow {
+    username: String,
+    email: String,
+    first_name: String,
+    last_name: String,
+    #[serde(default)]
+    is_admin: bool,
+    #[serde(default)]
+    disabled: bool,
+}
+
+/// Run an administrative command. `serve` and `gen-key` are handled by `main`.
+///
+/// # Errors
+///
+/// Returns an error if the database cannot be reached or the command fails.
+pub async fn run(command: Command, root: &Privileged, config: &AppConfig) -> Result<(), BoxError> {
+    match command {
+        Command::Migrate { dry_run } => {
+            let report = migrate::apply(root, &config.migrations.dir, dry_run).await?;
+
+            if dry_run {
+                for m in &report.pending {
+                    println!("would apply {:04}_{} ({})", m.version, m.name, m.path.display());
+                }
+                println!("{} pending migration(s)", report.pending.len());
+            } else {
+                println!("applied {} migration(s)", report.applied.len());
+            }
+        }
+        Command::User { command } => {
+            let db = root.get().await?;
+            user(command, &db).await?;
+        }
+        Command::Serve | Command::GenKey => {}
+    }
+
+    Ok(())
+}
+
+async fn user(command: UserCommand, db: &Surreal<Any>) -> Result<(), Error> {
+    match command {
+        UserCommand::Create { username, email, first_name, last_name, admin } => {
+            let password = match std::env::var("STICKERS_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => rpassword::prompt_password(format!("Password for {username}: "))
+                    .map_err(|e| Error::Validation(format!("cannot read password: {e}")))?,
+            };
+
+            if password.is_empty() {
+                return Err(Error::Validation("password must not be empty".to_string()));
+            }
+
+            db.query("
+                CREATE type::thing('user', string::trim($username)) SET
+                    email = $email,
+                    pass = crypto::argon2::generate($password),
+                    first_name = $first_name,
+                    last_name = $last_name,
+                    is_admin = $admin
+            ")
+                .bind(("username", username.as_str()))
+                .bind(("email", email))
+                .bind(("password", password))
+                .bind(("first_name", first_name))
+                .bind(("last_name", last_name))
+                .bind(("admin", admin))
+                .await?
+                .check()?;
+
+            println!("created user {}{}", username.trim(), if admin { " (admin)" } else { "" });
+        }
+        UserCommand::Promote { username, revoke } => {
+            set_flag(db, &username, "is_admin", !revoke).await?;
+            println!("{} {username}", if revoke { "demoted" } else { "promoted" });
+        }
+        UserCommand::Disable { username, undo } => {
+            set_flag(db, &username, "disabled", !undo).await?;
+            println!("{} {username}", if undo { "enabled" } else { "disabled" });
+        }
+        UserCommand::ResetTwoFactor { username } => {
+            if !two_factor::turn_off(db, &Thing::from(("user", username.as_str()))).await? {
+                return Err(Error::NotFound(format!("user {username}")));
+            }
+            println!("turned off two-factor authentication for {username}");
+        }
+        UserCommand::List => {
+            let users: Vec<UserRow> = db
+                .query("SELECT meta::id(id) AS username, email, first_name, last_name, is_admin, disabled FROM user ORDER BY username")
+                .await?
+                .take(0)?;
+
+            println!("{:<20} {:<30} {:<30} {:<6} {:<8}", "USERNAME", "EMAIL", "NAME", "ADMIN", "DISABLED");
+            for u in &users {
+                println!(
+                    "{:<20} {:<30} {:<30} {:<6} {:<8}",
+                    u.username,
+                    u.email,
+                    format!("{} {}", u.first_name, u.last_name).trim(),
+                    u.is_admin,
+                    u.disabled,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Set a boolean field on an existing user. `UPDATE` on a record id would create a missing
+/// user, so the record is matched with `WHERE` instead.
+async fn set_flag(db: &Surreal<Any>, username: &str, field: &'static str, value: bool) -> Result<(), Error> {
+    let updated: Vec<Thing> = db
+        .query(format!("UPDATE user SET {field} = $value WHERE id = type::thing('user', $username) RETURN VALUE id"))
+        .bind(("value", value))
+        .bind(("username", username))
+        .await?
+        .take(0)?;
+
+    if updated.is_empty() {
+        return Err(Error::NotFound(format!("user {username}")));
+    }
+
+    Ok(())
+}
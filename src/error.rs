@@ -1,72 +1,237 @@
 use std::fmt::Display;
 
-use axum::{response::IntoResponse, http::StatusCode};
-use deadpool::managed::RecycleError;
+use axum::{extract::Request, middleware::Next, response::{IntoResponse, Response}, http::{StatusCode, header}};
+use deadpool::managed::{PoolError, RecycleError};
+use maud::html;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum Error {
+    /// The request carries no session cookie.
     AuthNoToken,
-    AuthFailed,
-    DatabaseError,
-    PoolError,
-    HyperError,
-    HttpError,
+    /// The session could not be authenticated; the context says why.
+    AuthFailed(String),
+    /// The user is authenticated but not allowed to do this.
+    Forbidden(String),
+    NotFound(String),
+    /// The request conflicts with existing data, e.g. a taken username.
+    Conflict(String),
+    /// The request body exceeds `limit` bytes.
+    PayloadTooLarge { limit: u64 },
+    /// The request is well formed but its content is invalid.
+    Validation(String),
+    /// The action was repeated too soon; it may be retried after this many seconds.
+    RateLimited { retry_after_secs: u64 },
+    /// A dependency is temporarily unavailable.
+    Unavailable(String),
+    Database(surrealdb::Error),
+    Pool(Box<PoolError<Error>>),
+    /// The image server could not be reached.
+    Upstream(hyper_util::client::legacy::Error),
+    /// An identity provider failed or answered with something unusable.
+    Provider(String),
+    Http(http::Error),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
        match self {
             Self::AuthNoToken => write!(f, "No token provided"),
-            Self::AuthFailed => write!(f, "Authentication failed"),
-            Self::DatabaseError => write!(f, "Database error"),
-            Self::PoolError => write!(f, "Pool error"),
-            Self::HyperError => write!(f, "Hyper error"),
-            Self::HttpError => write!(f, "HTTP error"),
+            Self::AuthFailed(context) => write!(f, "Authentication failed: {context}"),
+            Self::Forbidden(context) => write!(f, "Forbidden: {context}"),
+            Self::NotFound(what) => write!(f, "Not found: {what}"),
+            Self::Conflict(context) => write!(f, "Conflict: {context}"),
+            Self::PayloadTooLarge { limit } => write!(f, "Payload exceeds the limit of {limit} bytes"),
+            Self::Validation(context) => write!(f, "Invalid request: {context}"),
+            Self::RateLimited { retry_after_secs } => write!(f, "Rate limited for {retry_after_secs} seconds"),
+            Self::Unavailable(context) => write!(f, "Service unavailable: {context}"),
+            Self::Database(e) => write!(f, "Database error: {e}"),
+            Self::Pool(e) => write!(f, "Pool error: {e}"),
+            Self::Upstream(e) => write!(f, "Image server error: {e}"),
+            Self::Provider(context) => write!(f, "Identity provider error: {context}"),
+            Self::Http(e) => write!(f, "HTTP error: {e}"),
        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Database(e) => Some(e),
+            Self::Pool(e) => Some(e.as_ref()),
+            Self::Upstream(e) => Some(e),
+            Self::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl Error {
     #[must_use]
     pub fn into_recycle_error(self) -> RecycleError<Self> {
-        println!("RecycleError: {self:?}");
+        tracing::warn!(error = ?self, "RecycleError");
         RecycleError::Backend(self)
     }
+
+    /// The HTTP status this error is reported with.
+    #[must_use]
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::AuthNoToken | Self::AuthFailed(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Pool(e) => match e.as_ref() {
+                PoolError::Backend(e) => e.status(),
+                PoolError::Timeout(_) | PoolError::Closed | PoolError::NoRuntimeSpecified => StatusCode::SERVICE_UNAVAILABLE,
+                PoolError::PostCreateHook(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Self::Upstream(_) | Self::Provider(_) => StatusCode::BAD_GATEWAY,
+            Self::Database(e) if is_connection_error(e) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Database(_) | Self::Http(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether retrying the operation may succeed, e.g. the database connection dropped or
+    /// no pooled connection was available in time.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Unavailable(_) => true,
+            Self::Database(e) => is_connection_error(e),
+            Self::Pool(e) => match e.as_ref() {
+                PoolError::Backend(e) => e.is_transient(),
+                PoolError::Timeout(_) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether the error means the underlying connection is broken and must not be reused.
+    #[must_use]
+    pub fn is_connection_error(&self) -> bool {
+        matches!(self, Self::Database(e) if is_connection_error(e))
+    }
+
+    /// Message safe to show to clients; server side details are only logged.
+    #[must_use]
+    pub fn public_message(&self) -> String {
+        match self {
+            Self::AuthNoToken | Self::AuthFailed(_) => "Invalid credentials.".to_string(),
+            Self::Forbidden(_) => "You are not allowed to do that.".to_string(),
+            Self::NotFound(_) => "The page you are looking for does not exist.".to_string(),
+            Self::Conflict(context) | Self::Validation(context) => context.clone(),
+            Self::PayloadTooLarge { limit } => format!("The file is too large, the limit is {limit} bytes."),
+            Self::RateLimited { retry_after_secs } => format!("Please wait {retry_after_secs} seconds before trying again."),
+            Self::Provider(_) => "Could not sign in with that provider, please try again.".to_string(),
+            _ => self.status().canonical_reason().unwrap_or("Error").to_string(),
+        }
+    }
+}
+
+/// Whether a SurrealDB error comes from the transport rather than from the query.
+#[must_use]
+pub fn is_connection_error(e: &surrealdb::Error) -> bool {
+    use surrealdb::error::Api;
+
+    matches!(e, surrealdb::Error::Api(Api::Ws(_) | Api::Http(_) | Api::ConnectionUninitialised))
 }
 
 impl From<hyper_util::client::legacy::Error> for Error {
     fn from(e: hyper_util::client::legacy::Error) -> Self {
-        println!("Hyper Error: {e:?}");
-        Self::HyperError
+        Self::Upstream(e)
     }
 }
 
 impl From<http::Error> for Error {
     fn from(e: http::Error) -> Self {
-        println!("HTTP Error: {e:?}");
-        Self::HttpError
+        Self::Http(e)
     }
 }
 
-impl From<deadpool::managed::PoolError<Error>> for Error {
-    fn from(e: deadpool::managed::PoolError<Error>) -> Self {
-        println!("PoolError: {e:?}");
-        Self::PoolError
+impl From<PoolError<Error>> for Error {
+    fn from(e: PoolError<Error>) -> Self {
+        Self::Pool(Box::new(e))
     }
 }
 
 impl From<surrealdb::Error> for Error {
     fn from(e: surrealdb::Error) -> Self {
-        println!("SurrealDB Error: {e:?}");
-        Self::DatabaseError
+        Self::Database(e)
     }
 
 }
 
+/// Status and message of an error response, left in the response extensions so
+/// [`render_errors`] can pick the representation the client asked for.
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    pub status: StatusCode,
+    pub message: String,
+}
+
 impl IntoResponse for Error {
-    fn into_response(self) -> axum::response::Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        if status.is_server_error() {
+            tracing::error!(error = %self, source = ?std::error::Error::source(&self), "Request failed");
+        }
+
+        let report = ErrorReport { status, message: self.public_message() };
+        let mut response = (status, report.message.clone()).into_response();
+        response.extensions_mut().insert(report);
+
+        if let Self::RateLimited { retry_after_secs } = self {
+            response.headers_mut().insert(header::RETRY_AFTER, retry_after_secs.into());
+        }
+
+        response
     }
-}
\ No newline at end of file
+}
+
+/// Render error responses as a full HTML page, an HTMX fragment when `HX-Request` is set,
+/// or JSON for clients that only accept `application/json`.
+pub async fn render_errors(req: Request, next: Next) -> Response {
+    let htmx = req.headers().get("HX-Request").is_some();
+    let json = req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json") && !accept.contains("text/html"));
+
+    let response = next.run(req).await;
+
+    let Some(report) = response.extensions().get::<ErrorReport>().cloned() else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    let body = if json {
+        axum::Json(serde_json::json!({
+            "error": {
+                "status": report.status.as_u16(),
+                "message": report.message,
+            }
+        })).into_response()
+    } else if htmx {
+        html! {
+            div ."bg-red-100 border border-red-400 text-red-700 px-4 py-2 rounded relative" role="alert" {
+                (report.message)
+            }
+        }.into_response()
+    } else {
+        crate::template::error_page(report.status, &report.message).into_response()
+    };
+
+    let (body_parts, body) = body.into_parts();
+    parts.headers.extend(body_parts.headers);
+
+    Response::from_parts(parts, body)
+}
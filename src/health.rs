@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use axum::{body::Body, extract::State, http::{StatusCode, Uri}, response::IntoResponse, Extension, Json};
+use hyper_util::client::legacy::{Client, connect::HttpConnector};
+use serde::Serialize;
+
+use crate::{error::Error, state::Context};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    ok: bool,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    ready: bool,
+    draining: bool,
+    surreal: Check,
+    img_server: Check,
+}
+
+/// Liveness: the process is up and serving requests.
+pub async fn healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: SurrealDB answers a trivial query, the image server responds and the
+/// instance is not draining for shutdown.
+pub async fn readyz(State(state): State<Context>, client: Extension<Client<HttpConnector, Body>>) -> impl IntoResponse {
+    let (surreal, img_server) = tokio::join!(
+        probe(check_surreal(&state)),
+        probe(check_img_server(&state, &client)),
+    );
+
+    let draining = state.shutdown.is_draining();
+    let ready = !draining && surreal.ok && img_server.ok;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    (status, Json(Readiness { ready, draining, surreal, img_server }))
+}
+
+async fn probe(check: impl std::future::Future<Output = Result<(), String>>) -> Check {
+    let start = Instant::now();
+
+    let result = match tokio::time::timeout(PROBE_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {PROBE_TIMEOUT:?}")),
+    };
+
+    Check {
+        ok: result.is_ok(),
+        latency_ms: start.elapsed().as_millis(),
+        error: result.err(),
+    }
+}
+
+async fn check_surreal(state: &Context) -> Result<(), String> {
+    let db = state.surreal.get().await.map_err(|e| Error::from(e).to_string())?;
+
+    db.query("RETURN true").await
+        .and_then(surrealdb::Response::check)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+async fn check_img_server(state: &Context, client: &Client<HttpConnector, Body>) -> Result<(), String> {
+    let uri: Uri = state.img_server.url.parse().map_err(|e: http::uri::InvalidUri| e.to_string())?;
+
+    let req = hyper::Request::get(uri)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+
+    let res = client.request(req).await.map_err(|e| e.to_string())?;
+
+    if res.status().is_server_error() {
+        Err(format!("responded with {}", res.status()))
+    } else {
+        Ok(())
+    }
+}
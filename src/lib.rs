@@ -0,0 +1,448 @@
+#![deny(clippy::unwrap_used)]
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![deny(rust_2018_idioms, unsafe_code)]
+
+use auth::Session;
+use axum_extra::extract::PrivateCookieJar;
+use hyper_util::{rt::TokioExecutor, client::legacy::{Client, connect::HttpConnector}};
+use maud::{html, Markup};
+use axum::handler::HandlerWithoutStateExt;
+use axum::{Router, routing::{get, post}, response::IntoResponse, extract::{State, Path}, Form, http::{HeaderName, StatusCode, Uri}, Extension, body::Body};
+use state::Context;
+use template::Template;
+use tower_http::add_extension::AddExtensionLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+use tower_http::services::ServeDir;
+
+pub mod acme;
+pub mod pool;
+pub mod reset;
+pub mod retry;
+pub mod session_cache;
+pub mod sessions;
+pub mod auth;
+pub mod cli;
+pub mod config;
+pub mod shutdown;
+pub mod state;
+pub mod error;
+pub mod health;
+pub mod logging;
+pub mod mail;
+pub mod middleware;
+pub mod migrate;
+pub mod monitoring;
+pub mod oidc;
+pub mod template;
+pub mod tls;
+pub mod token;
+pub mod two_factor;
+pub mod validation;
+pub mod verify;
+
+/// Build the application router with every route and layer, ready to be served.
+///
+/// The image server client is created here so tests get exactly what `main` serves.
+#[must_use]
+pub fn build_app(state: Context) -> Router {
+    let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+    .http2_only(true)
+    .build_http::<Body>();
+
+    let auth : Router<Context> = Router::new()
+        .route("/signin", get(signin).post(perform_signin))
+        .route("/signup", get(signup).post(perform_signup))
+        .route("/forgot", get(reset::forgot).post(reset::request_reset))
+        .route("/reset/:token", get(reset::reset_form).post(reset::perform_reset))
+        .route("/2fa", get(two_factor::challenge_form).post(two_factor::perform_challenge))
+        .route("/oidc/:provider/start", get(oidc::start))
+        .route("/oidc/:provider/callback", get(oidc::callback))
+        .route_layer(middleware::from_fn_with_state(state.clone(), middleware::redirect_already_logged_in))
+        // Verification links are opened by signed in users too.
+        .route("/verify/resend", post(verify::resend))
+        .route("/verify/:token", get(verify::verify));
+
+    let mut upload : Router<Context> = Router::new()
+        .route("/upload", post(proxy_upload_to_middleware));
+
+    if state.restrict_unverified {
+        upload = upload.route_layer(middleware::from_fn_with_state(state.clone(), middleware::assert_is_verified));
+    }
+
+    let account : Router<Context> = Router::new()
+        .route("/2fa", get(two_factor::settings).post(two_factor::enable))
+        .route("/2fa/recovery", post(two_factor::regenerate))
+        .route("/2fa/disable", post(two_factor::disable))
+        .route("/sessions", get(sessions::list))
+        .route("/sessions/revoke-all", post(sessions::revoke_all))
+        .route("/sessions/:session/revoke", post(sessions::revoke));
+
+    let admin : Router<Context> = Router::new()
+        .route("/admin", get(admin))
+        .route_layer(middleware::from_fn_with_state(state.clone(), middleware::assert_is_admin));
+
+    Router::new()
+        .route("/", get(root))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/other", get(other))
+        .route("/signout", get(perform_signout))
+        .route("/about", get(about))
+        .route("/get/:id", get(proxy_get_to_middleware))
+        .merge(admin)
+        .merge(upload)
+        .nest("/auth", auth)
+        .nest("/account", account)
+        .fallback_service(ServeDir::new("./static/").fallback(not_found.into_service()))
+        .layer(middleware::from_fn_with_state(state.clone(), middleware::renew_session))
+        .layer(tower_http::compression::CompressionLayer::new())
+        .layer(middleware::from_fn(error::render_errors))
+        .layer(middleware::from_fn(monitoring::track_requests))
+        .route_layer(middleware::from_fn_with_state(state.clone(), middleware::insert_securiy_headers))
+        .layer(AddExtensionLayer::new(client))
+        .layer(PropagateRequestIdLayer::new(HeaderName::from_static(logging::REQUEST_ID_HEADER)))
+        .layer(TraceLayer::new_for_http().make_span_with(|req: &axum::extract::Request| {
+            let request_id = req.headers()
+                .get(logging::REQUEST_ID_HEADER)
+                .and_then(|id| id.to_str().ok())
+                .unwrap_or_default();
+
+            tracing::info_span!("request", method = %req.method(), uri = %req.uri(), request_id)
+        }))
+        .layer(SetRequestIdLayer::new(HeaderName::from_static(logging::REQUEST_ID_HEADER), MakeRequestUuid))
+        .with_state(state)
+}
+
+#[tracing::instrument(name = "proxy.get", skip_all, fields(id = %id))]
+async fn proxy_get_to_middleware(State(state): State<Context>, Path((id,)): Path<(String,)>, client: Extension<Client<HttpConnector, Body>>, req: axum::extract::Request) -> Result<impl IntoResponse, crate::error::Error> {    
+    let method = req.method().to_owned();
+    let (scheme, authority) = state.img_server.url.split_once("://").expect("Invalid img server address; format must be scheme://authority");
+
+    let uri = Uri::builder()
+        .scheme(scheme)
+        .authority(authority)
+        .path_and_query(format!("/{id}"))
+        .build().map_err(crate::error::Error::from)?;
+
+    let headers = req.headers().to_owned();
+    let request_id = req.extensions().get::<RequestId>().cloned();
+    let body = req.into_body();
+
+    let mut req = hyper::Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(body)
+        .map_err(crate::error::Error::from)?;
+
+    *req.headers_mut() = headers;
+    forward_request_id(&mut req, request_id);
+
+    let start = std::time::Instant::now();
+    let res = client.request(req).await;
+    monitoring::record_image_proxy("get", start.elapsed(), res.is_ok());
+
+    Ok(res.map_err(crate::error::Error::from)?.into_response())
+}
+
+#[tracing::instrument(name = "proxy.upload", skip_all)]
+async fn proxy_upload_to_middleware(State(state): State<Context>, client: Extension<Client<HttpConnector, Body>>, req: axum::extract::Request) -> Result<impl IntoResponse, crate::error::Error> {
+    let limit = state.img_server.max_upload_bytes;
+    let declared = req.headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+
+    if declared.is_some_and(|len| len > limit) {
+        return Err(crate::error::Error::PayloadTooLarge { limit });
+    }
+
+    let method = req.method().to_owned();
+    let (scheme, authority) = state.img_server.url.split_once("://").expect("Invalid img server address; format must be scheme://authority");
+
+    let uri = Uri::builder()
+        .scheme(scheme)
+        .authority(authority)
+        .path_and_query("/new")
+        .build()
+        .map_err(crate::error::Error::from)?;
+
+    let headers = req.headers().to_owned();
+    let request_id = req.extensions().get::<RequestId>().cloned();
+    // Bodies without a Content-Length are still cut off at the limit while streaming.
+    let body = Body::new(http_body_util::Limited::new(req.into_body(), usize::try_from(limit).unwrap_or(usize::MAX)));
+
+    let mut req = hyper::Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(body)
+        .map_err(crate::error::Error::from)?;
+
+    *req.headers_mut() = headers;
+    forward_request_id(&mut req, request_id);
+
+    let start = std::time::Instant::now();
+    let res = client.request(req).await;
+    monitoring::record_image_proxy("upload", start.elapsed(), res.is_ok());
+
+    Ok(res.map_err(crate::error::Error::from)?.into_response())
+}
+
+/// Make sure the image server sees the same request ID as our own logs.
+fn forward_request_id(req: &mut hyper::Request<Body>, request_id: Option<RequestId>) {
+    if let Some(request_id) = request_id {
+        req.headers_mut().insert(logging::REQUEST_ID_HEADER, request_id.into_header_value());
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+struct SignInInfo {
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+struct SignUpInfo {
+    username: String,
+    password: String,
+    first_name: String,
+    last_name: String,
+    email: String,
+}
+
+impl SignUpInfo {
+    fn validate(&self) -> validation::FieldErrors {
+        let mut errors = validation::FieldErrors::new(validation::SIGNUP_FIELDS);
+
+        validation::username(&mut errors, &self.username);
+        validation::password(&mut errors, &self.password, &self.username);
+        validation::name(&mut errors, "first_name", "First name", &self.first_name);
+        validation::name(&mut errors, "last_name", "Last name", &self.last_name);
+        validation::email(&mut errors, &self.email);
+
+        errors
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+struct User {
+    is_admin: Option<bool>,
+}
+
+async fn perform_signout(State(state): State<Context>, jar: PrivateCookieJar, session: Option<Session>) -> impl IntoResponse {
+    if let Some(session) = session {
+        if let Err(e) = sessions::close(&state, &session).await {
+            tracing::warn!(error = ?e, user = %session.id(), "Could not close the session on sign out");
+        }
+    }
+
+    let res = (
+        jar.remove(state.removal_cookie()),
+        StatusCode::OK,
+    ).into_response();
+
+    let (mut parts, body) = res.into_parts();
+
+    parts.headers.append("HX-Redirect", "/".parse().expect("Infallible"));
+
+    axum::response::Response::from_parts(parts, body)
+}
+
+#[tracing::instrument(skip_all, fields(username = %info.username))]
+async fn perform_signin(State(state): State<Context>, jar: PrivateCookieJar, device: sessions::Device, Form(info): Form<SignInInfo>) -> Result<impl IntoResponse, crate::error::Error> {
+    let db = state.db().await?;
+    
+    let sign_res = db.signin(state.scope(info)).await;
+
+    monitoring::record_signin(sign_res.is_ok());
+
+    match sign_res {
+        Ok(token) => {
+            let token = token.as_insecure_token().to_string();
+
+            if let Some(user) = two_factor::required(&db).await? {
+                let jar = two_factor::challenge(&state, jar, user, token)?;
+                let (mut parts, body) = (jar, StatusCode::OK).into_response().into_parts();
+
+                parts.headers.append("HX-Redirect", "/auth/2fa".parse().expect("Infallible"));
+
+                return Ok(axum::response::Response::from_parts(parts, body));
+            }
+
+            drop(db);
+            let cookie = sessions::open(&state, &token, &device).await?;
+
+            let res = (
+                jar.add(cookie),
+                StatusCode::OK,
+            ).into_response();
+
+            let (mut parts, body) = res.into_parts();
+
+            parts.headers.append("HX-Redirect", "/".parse().expect("Infallible"));
+            
+            Ok(axum::response::Response::from_parts(parts, body))
+        },
+        Err(e) => {
+            tracing::info!(error = ?e, "Auth error");
+            Ok((StatusCode::UNAUTHORIZED, html! {
+                div ."bg-red-100 border border-red-400 text-red-700 px-4 py-2 rounded relative" role="alert" {
+                    "Invalid credentials."
+                }
+            }).into_response())
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(username = %info.username))]
+async fn perform_signup(State(state): State<Context>, jar: PrivateCookieJar, device: sessions::Device, Form(info): Form<SignUpInfo>) -> Result<impl IntoResponse, crate::error::Error> {
+    let errors = info.validate();
+
+    if !errors.is_empty() {
+        return Ok(errors.into_response());
+    }
+
+    let db = state.db().await?;
+    let (username, email) = (info.username.clone(), info.email.clone());
+    
+    let sign_res = db.signup(state.scope(info)).await;
+    drop(db);
+
+    match sign_res {
+        Ok(token) => {
+            // Sent in the background like reset links, so the sign up does not wait on SMTP.
+            let mail_state = state.clone();
+            tokio::spawn(async move {
+                verify::send_after_signup(&mail_state, &username, &email).await;
+            });
+
+            let cookie = sessions::open(&state, token.as_insecure_token(), &device).await?;
+
+            let res = (
+                jar.add(cookie),
+                StatusCode::OK,
+            ).into_response();
+
+            let (mut parts, body) = res.into_parts();
+
+            parts.headers.append("HX-Redirect", "/".parse().expect("Infallible"));
+            
+            Ok(axum::response::Response::from_parts(parts, body))
+        },
+        Err(e) if crate::error::is_connection_error(&e) => Err(e.into()),
+        Err(e) => {
+            tracing::info!(error = ?e, "Sign up rejected");
+
+            let errors = validation::from_signup_error(&e).unwrap_or_else(|| {
+                let mut errors = validation::FieldErrors::new(validation::SIGNUP_FIELDS);
+                errors.set_general("Could not create the account, please try again.");
+                errors
+            });
+
+            Ok(errors.into_response())
+        }
+    }
+}
+
+async fn signup(b: Template) -> Markup {
+    b.render(html!{
+        div.flex.flex-col.justify-center.h-screen {
+            div."flex flex-col items-center" hx-ext="response-targets" {
+                form."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
+                    h1."text-4xl".font-bold {
+                        "Sign up"
+                    }
+                    div #err {}
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="username" type="text" placeholder="Username" {}
+                    (validation::field_error("username", None, false))
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="password" type="password" placeholder="Password" {}
+                    (validation::field_error("password", None, false))
+                    
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="first_name" type="text" placeholder="First name" {}
+                    (validation::field_error("first_name", None, false))
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="last_name" type="text" placeholder="Last name" {}
+                    (validation::field_error("last_name", None, false))
+
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="email" type="email" placeholder="Email" {}
+                    (validation::field_error("email", None, false))
+
+                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".w-full 
+                    hx-post="/auth/signup" "hx-target-401"="#err" "hx-target-422"="#err"
+                    {
+                        "Sign up"
+                    }
+                    
+                }
+            }
+        }
+    })  
+}
+
+async fn signin(State(state): State<Context>, b: Template) -> Markup {
+    b.render(html!{
+        div.flex.flex-col.justify-center.h-screen {
+            div."flex flex-col items-center" hx-ext="response-targets" {
+                form."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
+                    h1."text-4xl".font-bold {
+                        "Sign in"
+                    }
+                    div #err {}
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="username" type="text" placeholder="Username" {}
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="password" type="password" placeholder="Password" {}
+                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".w-full 
+                    hx-post="/auth/signin" "hx-target-401"="#err"
+                    {
+                        "Sign in"
+                    }
+                    @if state.mailer.is_some() {
+                        a."text-sm underline" href="/auth/forgot" { "Forgot password?" }
+                    }
+                    @for (name, label) in state.oidc.list() {
+                        a."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2 text-center".w-full href=(format!("/auth/oidc/{name}/start")) {
+                            "Sign in with " (label)
+                        }
+                    }
+                    
+                }
+            }
+        }
+    })  
+}
+
+async fn admin(b: Template, session: Session) -> Markup {
+    b.render(html!{
+        div."p-4".flex.flex-col {
+            h1."text-4xl".font-bold { "Hola, " (session.first_name()) "!" }
+        }
+    })  
+}
+
+async fn not_found(uri: Uri) -> crate::error::Error {
+    crate::error::Error::NotFound(uri.path().to_string())
+}
+
+async fn root(b: Template) -> Markup {
+    b.render(html!{
+        h1."text-4xl".font-bold ."h-[1000px]" {
+            "Hello, world!"
+        }
+    })  
+}
+
+async fn other(b: Template) -> Markup {
+    b.render(html!{
+        h1."text-4xl".font-bold ."h-[1000px]" {
+            "Other!"
+        }
+    })  
+}
+
+async fn about(b: Template) -> Markup {
+    b.render(html!{
+        h1."text-4xl".font-bold ."h-[1000px]" {
+            "About!"
+        }
+    })  
+}
+
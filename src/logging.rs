@@ -0,0 +1,19 @@
+use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::config::{LogConfig, LogFormat};
+
+/// Header carrying the per-request ID, set on incoming requests and forwarded to the image server.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Install the global `tracing` subscriber. `RUST_LOG` takes precedence over `log.filter`.
+pub fn init(config: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(&config.filter));
+
+    let registry = tracing_subscriber::registry().with(filter);
+
+    match config.format {
+        LogFormat::Pretty => registry.with(fmt::layer()).init(),
+        LogFormat::Json => registry.with(fmt::layer().json().with_current_span(true).with_span_list(false)).init(),
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{async_trait, BoxError};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::{Mailbox, header::ContentType}};
+
+use crate::config::MailConfig;
+
+/// Delivers plain text emails.
+#[async_trait]
+pub trait Mailer: std::fmt::Debug + Send + Sync {
+    /// Send `body` to `to`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the recipient is invalid or the email could not be delivered.
+    async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), BoxError>;
+}
+
+/// Sends emails over SMTP.
+#[derive(Clone)]
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// Create a mailer for `config`. No connection is made until the first email is sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the SMTP URL or the sender address is invalid.
+    pub fn new(config: &MailConfig) -> Result<Self, BoxError> {
+        Ok(Self {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)?.build(),
+            from: config.from.parse()?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    #[tracing::instrument(name = "mail.send", skip(self, body))]
+    async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), BoxError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+
+        self.transport.send(message).await?;
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for SmtpMailer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpMailer")
+            .field("from", &self.from)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An email kept by [`MemoryMailer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Keeps every email in memory instead of sending it, for tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMailer {
+    sent: Arc<Mutex<Vec<Email>>>,
+}
+
+impl MemoryMailer {
+    /// Every email sent so far, oldest first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the internal lock is poisoned.
+    #[must_use]
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().expect("Mailer lock poisoned").clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), BoxError> {
+        self.sent.lock().expect("Mailer lock poisoned").push(Email {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body,
+        });
+
+        Ok(())
+    }
+}
@@ -3,28 +3,15 @@
 #![warn(clippy::pedantic)]
 #![deny(rust_2018_idioms, unsafe_code)]
 
-use auth::Session;
-use axum_extra::extract::{PrivateCookieJar, cookie::Cookie};
 use axum_server::tls_rustls::RustlsConfig;
-use hyper_util::{rt::TokioExecutor, client::legacy::{Client, connect::HttpConnector}};
-use maud::{html, Markup};
-use axum::{Router, routing::{get, post}, response::{IntoResponse, Redirect}, extract::{State, Host, Path}, Form, http::{StatusCode, Uri}, BoxError, Extension, body::Body};
-use state::Context;
-use template::Template;
-use tower_http::add_extension::AddExtensionLayer;
-use surrealdb::opt::auth::Scope;
-use axum::handler::HandlerWithoutStateExt;
-use tower_http::services::ServeDir;
-
-pub mod pool;
-pub mod auth;
-pub mod state;
-pub mod error;
-pub mod middleware;
-pub mod template;
-
-#[derive(Clone, Copy)]
+use clap::Parser;
+use axum::{Router, routing::get, response::Redirect, extract::Host, http::{StatusCode, Uri}, BoxError, Extension};
+use stickers::{acme, cli, auth, config::{self, ServeMode}, logging, migrate, monitoring, pool, shutdown::Shutdown, state, tls};
+use std::{net::SocketAddr, time::Duration};
+
+#[derive(Clone)]
 struct Ports {
+    host: String,
     http: u16,
     https: u16,
 }
@@ -32,113 +19,148 @@ struct Ports {
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
-    let surreal = std::env::var("SURREAL").expect("SURREAL must be set");
-    let s_size = std::env::var("POOL_SIZE").expect("POOL_SIZE must be set");
-    let img_server = std::env::var("IMG_SERVER").expect("IMG_SERVER must be set");
 
-    let surreal = pool::Manager::new(surreal.as_str(), s_size.parse::<usize>().expect("Valid pool size"));
-    let state = state::Context::new(surreal, &img_server);
+    let cli = cli::Cli::parse();
 
-    let ports = Ports {
-        http: 80,
-        https: 443,
+    if let Some(cli::Command::GenKey) = cli.command {
+        println!("{}", config::generate_key());
+        return;
+    }
+
+    let purpose = cli.command.as_ref().map_or(config::Purpose::Serve, cli::Command::purpose);
+
+    let app_config = match config::AppConfig::load(purpose) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
     };
 
-    let config = RustlsConfig::from_pem_file(
-        "/Users/alejandro/Downloads/OsornioLOL/certificate.crt",
-        "/Users/alejandro/Downloads/OsornioLOL/private.key",
-    )
-    .await
-    .expect("Valid certificate and key");
+    logging::init(&app_config.log);
+
+    let surreal = pool::Manager::new(&app_config.surreal);
+    let root = pool::Privileged::new(&app_config.surreal, &surreal);
+
+    if let Some(command) = cli.command.filter(|command| !matches!(command, cli::Command::Serve)) {
+        if let Err(e) = cli::run(command, &root, &app_config).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
-    .http2_only(true)
-    .build_http::<Body>();
+    let shutdown = Shutdown::from_os_signals(Duration::from_secs(app_config.server.readiness_grace_secs));
 
-    tokio::spawn(redirect_http_to_https(ports));
+    if app_config.migrations.auto {
+        if let Err(e) = migrate::apply(&root, &app_config.migrations.dir, false).await {
+            tracing::error!(error = %e, "Failed to apply migrations");
+            std::process::exit(1);
+        }
+    }
 
-    let auth : Router<Context> = Router::new()
-        .route("/signin", get(signin).post(perform_signin))
-        .route("/signup", get(signup).post(perform_signup))
-        .route_layer(middleware::from_fn_with_state(state.clone(), middleware::redirect_already_logged_in));
+    if let Err(e) = auth::install(&root, &app_config).await {
+        tracing::error!(error = %e, "Failed to define the session token key");
+        std::process::exit(1);
+    }
 
-    let admin : Router<Context> = Router::new()
-        .route("/admin", get(admin))
-        .route_layer(middleware::from_fn_with_state(state.clone(), middleware::assert_is_admin));
+    let state = state::Context::new(surreal, root, &app_config, shutdown.clone());
+    let pool = state.surreal.clone();
+    tokio::spawn(pool::reap(pool.clone(), Duration::from_secs(app_config.surreal.reap_interval_secs)));
 
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/other", get(other))
-        .route("/signout", get(perform_signout))
-        .route("/about", get(about))
-        .route("/upload", post(proxy_upload_to_middleware))
-        .route("/get/:id", get(proxy_get_to_middleware))
-        .merge(admin)
-        .nest("/auth", auth)
-        .fallback_service(ServeDir::new("./static/"))
-        .layer(tower_http::compression::CompressionLayer::new())
-        .route_layer(middleware::from_fn(middleware::insert_securiy_headers))
-        .layer(AddExtensionLayer::new(client))
-        .with_state(state);
-        
-    axum_server::bind_rustls(format!("[::]:{}", ports.https).parse().expect("Invalid binding"), config)
-        .serve(app.into_make_service())
-        .await
-        .expect("Server failed");
-}
+    let drain_deadline = Duration::from_secs(app_config.server.shutdown_timeout_secs);
 
-async fn proxy_get_to_middleware(State(state): State<Context>, Path((id,)): Path<(String,)>, client: Extension<Client<HttpConnector, Body>>, req: axum::extract::Request) -> Result<impl IntoResponse, crate::error::Error> {    
-    let method = req.method().to_owned();
-    let (scheme, authority) = state.img_server.split_once("://").expect("Invalid img server address; format must be scheme://authority");
+    if app_config.metrics.enabled {
+        let handle = monitoring::install();
+        let addr = format!("{}:{}", app_config.metrics.host, app_config.metrics.port);
+        tokio::spawn(monitoring::serve(addr, handle, pool.clone(), shutdown.clone()));
+    }
 
-    let uri = Uri::builder()
-        .scheme(scheme)
-        .authority(authority)
-        .path_and_query(format!("/{id}"))
-        .build().map_err(crate::error::Error::from)?;
+    let ports = Ports {
+        host: app_config.server.host.clone(),
+        http: app_config.server.http_port,
+        https: app_config.server.https_port,
+    };
 
-    let headers = req.headers().to_owned();
-    let body = req.into_body();
+    let app = stickers::build_app(state);
 
-    let mut req = hyper::Request::builder()
-        .method(method)
-        .uri(uri)
-        .body(body)
-        .map_err(crate::error::Error::from)?;
+    match app_config.server.mode {
+        ServeMode::Tls => {
+            let challenges = acme::Challenges::default();
+            let redirect = tokio::spawn(redirect_http_to_https(ports.clone(), challenges.clone(), shutdown.clone()));
 
-    *req.headers_mut() = headers;
+            let acme = app_config.acme.enabled.then(|| acme::Acme::new(&app_config, challenges));
 
-    Ok(client.request(req).await.map_err(crate::error::Error::from)?.into_response())
-}
+            if let Some(acme) = &acme {
+                if let Err(e) = acme.ensure_certificate().await {
+                    tracing::error!(error = ?e, "ACME: could not obtain a certificate");
+                    std::process::exit(1);
+                }
+            }
+
+            let config = RustlsConfig::from_pem_file(&app_config.tls.cert, &app_config.tls.key)
+            .await
+            .expect("Valid certificate and key");
+
+            if let Some(acme) = acme {
+                tokio::spawn(acme.renew(config.clone()));
+            }
 
-async fn proxy_upload_to_middleware(State(state): State<Context>, client: Extension<Client<HttpConnector, Body>>, req: axum::extract::Request) -> Result<impl IntoResponse, crate::error::Error> {
-    let method = req.method().to_owned();
-    let (scheme, authority) = state.img_server.split_once("://").expect("Invalid img server address; format must be scheme://authority");
+            tokio::spawn(tls::watch_certificates(
+                config.clone(),
+                app_config.tls.cert.clone(),
+                app_config.tls.key.clone(),
+                Duration::from_secs(app_config.tls.reload_interval_secs),
+            ));
+
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown.requested().await;
+                    handle.graceful_shutdown(Some(drain_deadline));
+                }
+            });
 
-    let uri = Uri::builder()
-        .scheme(scheme)
-        .authority(authority)
-        .path_and_query("/new")
-        .build()
-        .map_err(crate::error::Error::from)?;
+            axum_server::bind_rustls(format!("{}:{}", ports.host, ports.https).parse().expect("Invalid binding"), config)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .expect("Server failed");
 
-    let headers = req.headers().to_owned();
-    let body = req.into_body();
+            if let Err(e) = redirect.await {
+                tracing::error!(error = ?e, "HTTP redirect server failed");
+            }
+        }
+        ServeMode::Plain => {
+            tracing::warn!(port = app_config.server.plain_port, "!!! INSECURE: serving over PLAIN HTTP. TLS, HSTS and Secure cookies are DISABLED. Never use this mode in production. !!!");
+
+            let listener = tokio::net::TcpListener::bind(format!("{}:{}", ports.host, app_config.server.plain_port)).await.expect("Failed to bind");
+            serve_until_drained(listener, app, shutdown, drain_deadline).await;
+        }
+    }
 
-    let mut req = hyper::Request::builder()
-        .method(method)
-        .uri(uri)
-        .body(body)
-        .map_err(crate::error::Error::from)?;
+    pool.close();
+    tracing::info!("Shutdown complete");
+}
 
-    *req.headers_mut() = headers;
+/// Serve `app` on `listener` until shutdown is requested, then give in-flight requests at most
+/// `deadline` to finish.
+async fn serve_until_drained(listener: tokio::net::TcpListener, app: Router, shutdown: Shutdown, deadline: Duration) {
+    // The peer address is recorded on the sessions opened through this server.
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown.clone().requested());
 
-    Ok(client.request(req).await.map_err(crate::error::Error::from)?.into_response())
+    tokio::select! {
+        result = server => result.expect("Server failed"),
+        () = shutdown.deadline(deadline) => tracing::warn!(?deadline, "Drain deadline exceeded, dropping remaining connections"),
+    }
 }
 
 #[allow(dead_code)]
-async fn redirect_http_to_https(ports: Ports) {
-    fn make_https(host: &str, uri: Uri, ports: Ports) -> Result<Uri, BoxError> {
+async fn redirect_http_to_https(ports: Ports, challenges: acme::Challenges, shutdown: Shutdown) {
+    fn make_https(host: &str, uri: Uri, ports: &Ports) -> Result<Uri, BoxError> {
         let mut uri_parts = uri.into_parts();
 
         uri_parts.scheme = Some(axum::http::uri::Scheme::HTTPS);
@@ -153,218 +175,23 @@ async fn redirect_http_to_https(ports: Ports) {
         Ok(Uri::from_parts(uri_parts)?)
     }
 
+    let listener = tokio::net::TcpListener::bind(format!("{}:{}", ports.host, ports.http)).await.expect("Failed to bind");
+
     let redirect = move |Host(host): Host, uri: Uri| async move {
-        match make_https(&host, uri, ports) {
+        match make_https(&host, uri, &ports) {
             Ok(uri) => Ok(Redirect::permanent(&uri.to_string())),
             Err(error) => {
-                println!("Redirect error: {error:?}");
+                tracing::warn!(?error, "Redirect error");
                 Err(StatusCode::BAD_REQUEST)
             }
         }
     };
 
-    let listener = tokio::net::TcpListener::bind(format!("[::]:{}", ports.http)).await.expect("Failed to bind");
-    axum::serve(listener, redirect.into_make_service())
-        .await
-        .expect("Server failed");
-}
-
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
-struct SignInInfo {
-    username: String,
-    password: String,
-}
-
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
-struct SignUpInfo {
-    username: String,
-    password: String,
-    first_name: String,
-    last_name: String,
-    email: String,
-}
-
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
-struct User {
-    is_admin: Option<bool>,
-}
-
-async fn perform_signout(jar: PrivateCookieJar) -> impl IntoResponse {
-    let res = (
-        jar.remove(Cookie::from("token")),
-        StatusCode::OK,
-    ).into_response();
-
-    let (mut parts, body) = res.into_parts();
-
-    parts.headers.append("HX-Redirect", "/".parse().expect("Infallible"));
-
-    axum::response::Response::from_parts(parts, body)
-}
-
-async fn perform_signin(State(state): State<Context>, jar: PrivateCookieJar, Form(info): Form<SignInInfo>) -> Result<impl IntoResponse, crate::error::Error> {
-    let db = state.surreal.get().await?;
-    
-    let sign_res = db.signin(Scope {
-        namespace: "demo",
-        database: "demo",
-        scope: "account",
-        params: info
-    }).await;
-
-    match sign_res {
-        Ok(token) => {
-            let res = (
-                jar.add(
-                    Cookie::build(("token", token.as_insecure_token().to_string()))
-                    .secure(true)
-                    .http_only(true)
-                    .path("/")
-                    .same_site(axum_extra::extract::cookie::SameSite::Strict)
-                    .build()
-                ),
-                StatusCode::OK,
-            ).into_response();
-
-            let (mut parts, body) = res.into_parts();
-
-            parts.headers.append("HX-Redirect", "/".parse().expect("Infallible"));
-            
-            Ok(axum::response::Response::from_parts(parts, body))
-        },
-        Err(e) => {
-            println!("Auth error: {e:?}");
-            Ok((StatusCode::UNAUTHORIZED, html! {
-                div ."bg-red-100 border border-red-400 text-red-700 px-4 py-2 rounded relative" role="alert" {
-                    "Invalid credentials."
-                }
-            }).into_response())
-        }
-    }
-}
-
-async fn perform_signup(State(state): State<Context>, jar: PrivateCookieJar, Form(info): Form<SignUpInfo>) -> Result<impl IntoResponse, crate::error::Error> {
-    let db = state.surreal.get().await?;
-    
-    let sign_res = db.signup(Scope {
-        namespace: "demo",
-        database: "demo",
-        scope: "account",
-        params: info
-    }).await;
-
-    match sign_res {
-        Ok(token) => {
-            let res = (
-                jar.add(
-                    Cookie::build(("token", token.as_insecure_token().to_string()))
-                    .secure(true)
-                    .http_only(true)
-                    .path("/")
-                    .same_site(axum_extra::extract::cookie::SameSite::Strict)
-                    .build()
-                ),
-                StatusCode::OK,
-            ).into_response();
-
-            let (mut parts, body) = res.into_parts();
-
-            parts.headers.append("HX-Redirect", "/".parse().expect("Infallible"));
-            
-            Ok(axum::response::Response::from_parts(parts, body))
-        },
-        Err(e) => {
-            println!("Auth error: {e:?}");
-            Ok((StatusCode::UNAUTHORIZED, html! {
-                div ."bg-red-100 border border-red-400 text-red-700 px-4 py-2 rounded relative" role="alert" {
-                    "Invalid credentials."
-                }
-            }).into_response())
-        }
-    }
-}
-
-async fn signup(b: Template) -> Markup {
-    b.render(html!{
-        div.flex.flex-col.justify-center.h-screen {
-            div."flex flex-col items-center" hx-ext="response-targets" {
-                form."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
-                    h1."text-4xl".font-bold {
-                        "Sign up"
-                    }
-                    div #err {}
-                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="username" type="text" placeholder="Username" {}
-                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="password" type="password" placeholder="Password" {}
-                    
-                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="first_name" type="text" placeholder="First name" {}
-                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="last_name" type="text" placeholder="Last name" {}
-
-                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="email" type="email" placeholder="Email" {}
-
-                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".w-full 
-                    hx-post="/auth/signup" "hx-target-401"="#err"
-                    {
-                        "Sign up"
-                    }
-                    
-                }
-            }
-        }
-    })  
-}
-
-async fn signin(b: Template) -> Markup {
-    b.render(html!{
-        div.flex.flex-col.justify-center.h-screen {
-            div."flex flex-col items-center" hx-ext="response-targets" {
-                form."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
-                    h1."text-4xl".font-bold {
-                        "Sign in"
-                    }
-                    div #err {}
-                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="username" type="text" placeholder="Username" {}
-                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="password" type="password" placeholder="Password" {}
-                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".w-full 
-                    hx-post="/auth/signin" "hx-target-401"="#err"
-                    {
-                        "Sign in"
-                    }
-                    
-                }
-            }
-        }
-    })  
-}
-
-async fn admin(b: Template, session: Session) -> Markup {
-    b.render(html!{
-        div."p-4".flex.flex-col {
-            h1."text-4xl".font-bold { "Hola, " (session.first_name()) "!" }
-        }
-    })  
-}
-
-async fn root(b: Template) -> Markup {
-    b.render(html!{
-        h1."text-4xl".font-bold ."h-[1000px]" {
-            "Hello, world!"
-        }
-    })  
-}
-
-async fn other(b: Template) -> Markup {
-    b.render(html!{
-        h1."text-4xl".font-bold ."h-[1000px]" {
-            "Other!"
-        }
-    })  
-}
+    // ACME HTTP-01 challenges must be answered over plain HTTP, everything else is redirected.
+    let app = Router::new()
+        .route(&format!("{}:token", acme::CHALLENGE_PREFIX), get(acme::serve_challenge))
+        .fallback(redirect)
+        .layer(Extension(challenges));
 
-async fn about(b: Template) -> Markup {
-    b.render(html!{
-        h1."text-4xl".font-bold ."h-[1000px]" {
-            "About!"
-        }
-    })  
+    serve_until_drained(listener, app, shutdown, Duration::ZERO).await;
 }
-
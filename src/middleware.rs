@@ -1,8 +1,9 @@
 use axum::{extract::{State, Request}, response::{Redirect, IntoResponse, Response}, middleware::Next};
 pub use axum::middleware::{from_fn_with_state, from_fn};
-use http::StatusCode;
+use axum_extra::extract::PrivateCookieJar;
+use http::{header::SET_COOKIE, StatusCode};
 
-use crate::{state::Context, auth::Session, error};
+use crate::{state::Context, auth::{Renewal, Session}, error};
 
 pub async fn redirect_already_logged_in(_: State<Context>, session: Result<Session, error::Error>, req: Request, next: Next) -> Response {
     if session.is_ok() {
@@ -12,13 +13,50 @@ pub async fn redirect_already_logged_in(_: State<Context>, session: Result<Sessi
     }
 }
 
-pub async fn assert_is_admin(_: State<Context>, session: Result<Session, error::Error>, req: Request, next: Next) -> Response {
+/// Only let through administrators, sending those who still have to turn on two-factor
+/// authentication to its settings when `auth.require_admin_two_factor` is on.
+pub async fn assert_is_admin(State(state): State<Context>, session: Result<Session, error::Error>, req: Request, next: Next) -> Response {
     match session {
+        Ok(session) if session.is_admin() && state.require_admin_two_factor && !session.has_two_factor() => redirect(&req, "/account/2fa"),
         Ok(session) if session.is_admin() => next.run(req).await,
         _ => redirect(&req, "/")
     }
 }
 
+/// Only let through signed in users whose session is not restricted by an unverified email.
+pub async fn assert_is_verified(_: State<Context>, session: Result<Session, error::Error>, req: Request, next: Next) -> Response {
+    match session {
+        Ok(session) if !session.is_restricted() => next.run(req).await,
+        Ok(_) => error::Error::Forbidden("verify your email address to upload stickers".to_string()).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Send the session cookie renewed while handling the request, unless the handler set or
+/// removed the session cookie itself, e.g. by signing in or out.
+pub async fn renew_session(State(state): State<Context>, jar: PrivateCookieJar, mut req: Request, next: Next) -> Response {
+    let renewal = Renewal::default();
+    req.extensions_mut().insert(renewal.clone());
+
+    let response = next.run(req).await;
+
+    let Some(cookie) = renewal.take() else {
+        return response;
+    };
+
+    let prefix = format!("{}=", state.cookie.name);
+    let replaced = response.headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .any(|value| value.to_str().is_ok_and(|value| value.starts_with(&prefix)));
+
+    if replaced {
+        return response;
+    }
+
+    (jar.add(cookie), response).into_response()
+}
+
 fn redirect(req: &Request, to: &str) -> Response {
     if req.headers().get("HX-Request").is_some() {
         let (mut parts, body) = StatusCode::OK.into_response().into_parts();
@@ -27,23 +65,26 @@ fn redirect(req: &Request, to: &str) -> Response {
 
         Response::from_parts(parts, body)
     } else {
-        Redirect::to("/").into_response()
+        Redirect::to(to).into_response()
     }
 }
 
-/// Insert security headers into the response.
+/// Insert security headers into the response. HSTS is only sent when serving over TLS.
 ///
 /// # Panics
 ///
 /// This function should never panic. It panics if it fails to parse any of the headers.
-pub async fn insert_securiy_headers(req: Request, next: Next) -> Response {
+pub async fn insert_securiy_headers(State(state): State<Context>, req: Request, next: Next) -> Response {
     let mut response = next.run(req).await;
     
     response.headers_mut().insert("X-Frame-Options", "DENY".parse().expect("Infallible"));
     response.headers_mut().insert("X-XSS-Protection", "1; mode=block".parse().expect("Infallible"));
     response.headers_mut().insert("X-Content-Type-Options", "nosniff".parse().expect("Infallible"));
     response.headers_mut().insert("Referrer-Policy", "no-referrer".parse().expect("Infallible"));
-    response.headers_mut().insert("Strict-Transport-Security", "max-age=63072000; includeSubDomains".parse().expect("Infallible"));
+
+    if state.secure {
+        response.headers_mut().insert("Strict-Transport-Security", "max-age=63072000; includeSubDomains".parse().expect("Infallible"));
+    }
     
     response
 }
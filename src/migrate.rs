@@ -0,0 +1,191 @@
+use std::{fmt::Display, path::{Path, PathBuf}};
+
+use sha2::{Digest, Sha256};
+use surrealdb::{Surreal, engine::any::Any};
+
+use crate::pool::Privileged;
+
+/// A `NNNN_name.surql` file in the migrations directory.
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub version: u32,
+    pub name: String,
+    pub path: PathBuf,
+    pub sql: String,
+    pub checksum: String,
+}
+
+/// A migration recorded in the `_migrations` table.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct Applied {
+    version: u32,
+    name: String,
+    checksum: String,
+}
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Io(PathBuf, std::io::Error),
+    /// A file in the migrations directory does not follow `NNNN_name.surql`.
+    InvalidName(PathBuf),
+    DuplicateVersion(u32),
+    /// An applied migration was edited after it ran.
+    ChecksumMismatch { version: u32, name: String },
+    /// An applied migration no longer exists on disk.
+    Missing { version: u32, name: String },
+    Connect(crate::error::Error),
+    /// The `_migrations` table cannot be defined or read.
+    History(surrealdb::Error),
+    Database(u32, surrealdb::Error),
+}
+
+impl Display for MigrateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            Self::InvalidName(path) => write!(f, "{} is not named NNNN_name.surql", path.display()),
+            Self::DuplicateVersion(version) => write!(f, "more than one migration has version {version:04}"),
+            Self::ChecksumMismatch { version, name } => write!(f, "migration {version:04}_{name} was modified after being applied; add a new migration instead"),
+            Self::Missing { version, name } => write!(f, "migration {version:04}_{name} was applied but its file is missing"),
+            Self::Connect(e) => write!(f, "cannot open a privileged connection: {e}"),
+            Self::History(e) => write!(f, "cannot read applied migrations: {e}"),
+            Self::Database(version, e) => write!(f, "migration {version:04} failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MigrateError {}
+
+/// Outcome of a migration run.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub applied: Vec<Migration>,
+    pub pending: Vec<Migration>,
+}
+
+/// Read and order every migration in `dir`.
+///
+/// # Errors
+///
+/// Returns an error if the directory cannot be read, a file is misnamed or two files share a version.
+pub fn discover(dir: &Path) -> Result<Vec<Migration>, MigrateError> {
+    let entries = std::fs::read_dir(dir).map_err(|e| MigrateError::Io(dir.to_path_buf(), e))?;
+
+    let mut migrations = Vec::new();
+
+    for entry in entries {
+        let path = entry.map_err(|e| MigrateError::Io(dir.to_path_buf(), e))?.path();
+
+        if path.extension().and_then(|ext| ext.to_str()) != Some("surql") {
+            continue;
+        }
+
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        let Some((version, name)) = stem.split_once('_') else {
+            return Err(MigrateError::InvalidName(path));
+        };
+        let Ok(version) = version.parse::<u32>() else {
+            return Err(MigrateError::InvalidName(path));
+        };
+        if version == 0 || name.is_empty() {
+            return Err(MigrateError::InvalidName(path));
+        }
+
+        let sql = std::fs::read_to_string(&path).map_err(|e| MigrateError::Io(path.clone(), e))?;
+
+        migrations.push(Migration {
+            version,
+            name: name.to_string(),
+            checksum: format!("{:x}", Sha256::digest(sql.as_bytes())),
+            sql,
+            path,
+        });
+    }
+
+    migrations.sort_by_key(|m| m.version);
+
+    if let Some(pair) = migrations.windows(2).find(|pair| pair[0].version == pair[1].version) {
+        return Err(MigrateError::DuplicateVersion(pair[0].version));
+    }
+
+    Ok(migrations)
+}
+
+/// Run the migrations in `dir` over a root connection from `root`, see [`run`].
+///
+/// # Errors
+///
+/// Returns an error if the privileged connection cannot be opened or [`run`] fails.
+pub async fn apply(root: &Privileged, dir: &Path, dry_run: bool) -> Result<Report, MigrateError> {
+    let db = root.get().await.map_err(MigrateError::Connect)?;
+
+    run(&db, dir, dry_run).await
+}
+
+/// Apply every pending migration in `dir`, in order, each in its own transaction together
+/// with its `_migrations` record. With `dry_run` nothing is written and the pending
+/// migrations are only reported.
+///
+/// `db` must be signed in with enough privileges to define the schema.
+///
+/// # Errors
+///
+/// Refuses to run if an applied migration changed or disappeared, and stops at the first
+/// migration that fails.
+pub async fn run(db: &Surreal<Any>, dir: &Path, dry_run: bool) -> Result<Report, MigrateError> {
+    let migrations = discover(dir)?;
+
+    db.query("
+        DEFINE TABLE _migrations SCHEMAFULL PERMISSIONS NONE;
+        DEFINE FIELD version ON _migrations TYPE int;
+        DEFINE FIELD name ON _migrations TYPE string;
+        DEFINE FIELD checksum ON _migrations TYPE string;
+        DEFINE FIELD applied_at ON _migrations TYPE datetime DEFAULT time::now();
+    ")
+        .await
+        .and_then(surrealdb::Response::check)
+        .map_err(MigrateError::History)?;
+
+    let applied: Vec<Applied> = db.query("SELECT version, name, checksum FROM _migrations ORDER BY version")
+        .await
+        .and_then(|mut res| res.take(0))
+        .map_err(MigrateError::History)?;
+
+    for record in &applied {
+        match migrations.iter().find(|m| m.version == record.version) {
+            Some(m) if m.checksum == record.checksum => {}
+            Some(_) => return Err(MigrateError::ChecksumMismatch { version: record.version, name: record.name.clone() }),
+            None => return Err(MigrateError::Missing { version: record.version, name: record.name.clone() }),
+        }
+    }
+
+    let pending = migrations
+        .into_iter()
+        .filter(|m| !applied.iter().any(|a| a.version == m.version))
+        .collect::<Vec<_>>();
+
+    if dry_run {
+        return Ok(Report { applied: Vec::new(), pending });
+    }
+
+    let mut report = Report::default();
+
+    for migration in pending {
+        tracing::info!(version = migration.version, name = %migration.name, "Applying migration");
+
+        db.query("BEGIN TRANSACTION")
+            .query(migration.sql.as_str())
+            .query("CREATE type::thing('_migrations', $version) SET version = $version, name = $name, checksum = $checksum")
+            .query("COMMIT TRANSACTION")
+            .bind(("version", migration.version))
+            .bind(("name", migration.name.as_str()))
+            .bind(("checksum", migration.checksum.as_str()))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(|e| MigrateError::Database(migration.version, e))?;
+
+        report.applied.push(migration);
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use axum::{extract::{MatchedPath, Request, State}, middleware::Next, response::Response, routing::get, Router};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::{pool::SurrealManager, shutdown::Shutdown};
+
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Install the global Prometheus recorder.
+///
+/// # Panics
+///
+/// Panics if a recorder was already installed.
+#[must_use]
+pub fn install() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("duration_seconds".to_string()), LATENCY_BUCKETS)
+        .expect("Non empty buckets")
+        .install_recorder()
+        .expect("Metrics recorder already installed")
+}
+
+/// Count requests and record their latency per route.
+pub async fn track_requests(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let path = req.extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+
+    let response = next.run(req).await;
+    let status = response.status().as_u16().to_string();
+
+    metrics::counter!("http_requests_total", "method" => method.clone(), "path" => path.clone(), "status" => status).increment(1);
+    metrics::histogram!("http_request_duration_seconds", "method" => method, "path" => path).record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Record the outcome of a request proxied to the image server.
+pub fn record_image_proxy(operation: &'static str, elapsed: Duration, ok: bool) {
+    metrics::histogram!("image_proxy_duration_seconds", "operation" => operation).record(elapsed.as_secs_f64());
+
+    if !ok {
+        metrics::counter!("image_proxy_errors_total", "operation" => operation).increment(1);
+    }
+}
+
+/// Record a sign in attempt.
+pub fn record_signin(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    metrics::counter!("signin_total", "result" => result).increment(1);
+}
+
+/// Record whether a session was found in the session cache.
+pub fn record_session_cache(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    metrics::counter!("session_cache_total", "result" => result).increment(1);
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    handle: PrometheusHandle,
+    pool: SurrealManager,
+}
+
+/// Serve `/metrics` on `addr` until shutdown is requested.
+///
+/// # Panics
+///
+/// Panics if the address cannot be bound.
+pub async fn serve(addr: String, handle: PrometheusHandle, pool: SurrealManager, shutdown: Shutdown) {
+    let app = Router::new()
+        .route("/metrics", get(render))
+        .with_state(MetricsState { handle, pool });
+
+    let listener = tokio::net::TcpListener::bind(&addr).await.expect("Failed to bind metrics listener");
+    tracing::info!(%addr, "Serving metrics");
+
+    axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown.requested())
+        .await
+        .expect("Metrics server failed");
+}
+
+#[allow(clippy::cast_precision_loss)]
+async fn render(State(state): State<MetricsState>) -> String {
+    let status = state.pool.status();
+
+    metrics::gauge!("surreal_pool_max_size").set(status.max_size as f64);
+    metrics::gauge!("surreal_pool_size").set(status.size as f64);
+    metrics::gauge!("surreal_pool_available").set(status.available as f64);
+    metrics::gauge!("surreal_pool_waiting").set(status.waiting as f64);
+
+    state.handle.render()
+}
@@ -0,0 +1,467 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{extract::{Path, Query, State}, response::{IntoResponse, Redirect, Response}};
+use axum_extra::extract::{PrivateCookieJar, cookie::{Cookie, SameSite}};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, jwk::{Jwk, JwkSet}};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use surrealdb::{Surreal, engine::any::Any, sql::Thing};
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::{auth, config::{OidcConfig, OidcProvider}, error::Error, monitoring, sessions::{self, Device}, state::{Context, State as AppState}, token, two_factor};
+
+/// Time a user has to come back from the provider.
+const FLOW_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The configured providers and the HTTP client used to talk to them.
+pub struct Providers {
+    client: reqwest::Client,
+    providers: Vec<Provider>,
+}
+
+struct Provider {
+    config: OidcProvider,
+    /// Discovered on first use and kept for the life of the process.
+    metadata: OnceCell<Metadata>,
+    /// Signing keys, fetched on first use and again when a token names an unknown key.
+    keys: RwLock<Option<JwkSet>>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// An authorization in progress, kept in a private cookie until the provider sends the user back.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Flow {
+    provider: String,
+    state: String,
+    nonce: String,
+    verifier: String,
+    expires_at: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The ID token claims used to find or create the account.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct IdClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    /// A boolean, or a string with some providers.
+    email_verified: Option<serde_json::Value>,
+    name: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+    preferred_username: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct Account {
+    id: Thing,
+    #[serde(default)]
+    disabled: bool,
+    #[serde(default)]
+    totp_enabled: bool,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn provider_error(what: &'static str) -> impl Fn(reqwest::Error) -> Error {
+    move |e| Error::Provider(format!("{what}: {e}"))
+}
+
+impl Providers {
+    /// Set up the providers of `config`. Nothing is fetched until the first sign in.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the TLS backend of the HTTP client cannot be initialized.
+    #[must_use]
+    pub fn new(config: &OidcConfig) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("HTTP client for OIDC providers"),
+            providers: config.providers.iter().map(|config| Provider { config: config.clone(), metadata: OnceCell::new(), keys: RwLock::new(None) }).collect(),
+        }
+    }
+
+    /// Name and label of every provider, for the sign in buttons.
+    pub fn list(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.providers.iter().map(|p| (p.config.name.as_str(), p.label()))
+    }
+
+    fn get(&self, name: &str) -> Result<&Provider, Error> {
+        self.providers
+            .iter()
+            .find(|p| p.config.name == name)
+            .ok_or_else(|| Error::NotFound(format!("OIDC provider {name}")))
+    }
+}
+
+impl std::fmt::Debug for Providers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Providers")
+            .field("providers", &self.providers.iter().map(|p| &p.config.name).collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Provider {
+    fn label(&self) -> &str {
+        self.config.label.as_deref().unwrap_or(&self.config.name)
+    }
+
+    async fn metadata(&self, client: &reqwest::Client) -> Result<&Metadata, Error> {
+        self.metadata.get_or_try_init(|| async {
+            let issuer = self.config.issuer.trim_end_matches('/');
+
+            let metadata: Metadata = client.get(format!("{issuer}/.well-known/openid-configuration"))
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(provider_error("discovery"))?
+                .json()
+                .await
+                .map_err(provider_error("discovery"))?;
+
+            if metadata.issuer.trim_end_matches('/') != issuer {
+                return Err(Error::Provider(format!("discovery: issuer {:?} does not match the configured {issuer:?}", metadata.issuer)));
+            }
+
+            Ok(metadata)
+        }).await
+    }
+
+    /// The signing key named `kid`, or the only key when the token names none. The cached
+    /// keys are fetched again when they do not have it, so rotated keys are picked up.
+    async fn key(&self, client: &reqwest::Client, metadata: &Metadata, kid: Option<&str>) -> Result<Jwk, Error> {
+        if let Some(jwk) = self.keys.read().await.as_ref().and_then(|keys| find_key(keys, kid)) {
+            return Ok(jwk);
+        }
+
+        let keys: JwkSet = client.get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(provider_error("keys"))?
+            .json()
+            .await
+            .map_err(provider_error("keys"))?;
+
+        let jwk = find_key(&keys, kid);
+        *self.keys.write().await = Some(keys);
+
+        jwk.ok_or_else(|| Error::AuthFailed("no key for the ID token".to_string()))
+    }
+
+    /// Trade the authorization code for an ID token and return its claims once the
+    /// signature, issuer, audience, expiry and nonce check out.
+    async fn exchange(&self, client: &reqwest::Client, code: &str, flow: &Flow, redirect_uri: &str) -> Result<IdClaims, Error> {
+        let metadata = self.metadata(client).await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &flow.verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let tokens: TokenResponse = client.post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(provider_error("token exchange"))?
+            .json()
+            .await
+            .map_err(provider_error("token exchange"))?;
+
+        let header = jsonwebtoken::decode_header(&tokens.id_token).map_err(|e| Error::AuthFailed(format!("ID token: {e}")))?;
+
+        // Shared secret algorithms would let anyone holding the client secret forge tokens.
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(Error::AuthFailed(format!("ID token signed with {:?}", header.alg)));
+        }
+
+        let jwk = self.key(client, metadata, header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|e| Error::AuthFailed(format!("ID token key: {e}")))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = jsonwebtoken::decode::<IdClaims>(&tokens.id_token, &key, &validation)
+            .map_err(|e| Error::AuthFailed(format!("ID token: {e}")))?
+            .claims;
+
+        if claims.nonce.as_deref() != Some(flow.nonce.as_str()) {
+            return Err(Error::AuthFailed("ID token nonce does not match".to_string()));
+        }
+
+        Ok(claims)
+    }
+}
+
+fn find_key(keys: &JwkSet, kid: Option<&str>) -> Option<Jwk> {
+    match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }.cloned()
+}
+
+fn redirect_uri(state: &AppState, provider: &str) -> String {
+    format!("{}/auth/oidc/{provider}/callback", state.base_url)
+}
+
+fn flow_cookie_name(state: &AppState) -> String {
+    format!("{}_oidc", state.cookie.name)
+}
+
+/// `Lax`, not `Strict`: the provider sends the user back with a cross site redirect.
+fn flow_cookie(state: &AppState, value: String) -> Cookie<'static> {
+    Cookie::build((flow_cookie_name(state), value))
+        .secure(state.secure)
+        .http_only(true)
+        .path("/auth/oidc")
+        .same_site(SameSite::Lax)
+        .build()
+}
+
+/// Send the user to the provider with a fresh state, nonce and PKCE challenge.
+///
+/// # Panics
+///
+/// This function should never panic. It panics if the flow cannot be serialized.
+#[tracing::instrument(skip_all, fields(provider = %name))]
+pub async fn start(State(state): State<Context>, jar: PrivateCookieJar, Path(name): Path<String>) -> Result<Response, Error> {
+    let provider = state.oidc.get(&name)?;
+    let metadata = provider.metadata(&state.oidc.client).await?;
+
+    let flow = Flow {
+        provider: name.clone(),
+        state: token::generate(),
+        nonce: token::generate(),
+        verifier: token::generate(),
+        expires_at: now() + FLOW_TTL.as_secs(),
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(flow.verifier.as_bytes()));
+
+    let mut url = reqwest::Url::parse(&metadata.authorization_endpoint)
+        .map_err(|e| Error::Provider(format!("authorization endpoint: {e}")))?;
+
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.config.client_id)
+        .append_pair("redirect_uri", &redirect_uri(&state, &name))
+        .append_pair("scope", &provider.config.scopes.join(" "))
+        .append_pair("state", &flow.state)
+        .append_pair("nonce", &flow.nonce)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    let value = serde_json::to_string(&flow).expect("Flow serializes");
+
+    Ok((jar.add(flow_cookie(&state, value)), Redirect::to(url.as_str())).into_response())
+}
+
+/// Finish the authorization code flow: check the state, trade the code for a verified ID
+/// token, find or create the linked account and sign it in.
+#[tracing::instrument(skip_all, fields(provider = %name))]
+pub async fn callback(State(state): State<Context>, jar: PrivateCookieJar, device: Device, Path(name): Path<String>, Query(params): Query<CallbackParams>) -> Result<Response, Error> {
+    let flow = jar.get(&flow_cookie_name(&state)).and_then(|cookie| serde_json::from_str::<Flow>(cookie.value()).ok());
+    let jar = jar.remove(flow_cookie(&state, String::new()));
+
+    if let Some(error) = params.error {
+        tracing::info!(error, "Provider refused the sign in");
+        return Err(Error::Validation("The sign in was cancelled or refused by the provider.".to_string()));
+    }
+
+    let flow = flow.filter(|flow| flow.provider == name && flow.expires_at > now() && params.state.as_deref() == Some(flow.state.as_str()));
+
+    let (Some(flow), Some(code)) = (flow, params.code) else {
+        return Err(Error::Validation("This sign in expired, please try again.".to_string()));
+    };
+
+    let provider = state.oidc.get(&name)?;
+    let claims = provider.exchange(&state.oidc.client, &code, &flow, &redirect_uri(&state, &name)).await;
+
+    monitoring::record_signin(claims.is_ok());
+
+    let claims = claims?;
+    let db = state.privileged().await?;
+    let account = account(&db, provider, &claims).await?;
+    drop(db);
+
+    if account.disabled {
+        return Err(Error::Forbidden("account is disabled".to_string()));
+    }
+
+    tracing::info!(user = %account.id, "Signed in with OIDC");
+
+    let token = auth::mint(&state, &account.id, auth::SESSION)?;
+
+    if account.totp_enabled {
+        let jar = two_factor::challenge(&state, jar, account.id, token)?;
+        return Ok((jar, Redirect::to("/auth/2fa")).into_response());
+    }
+
+    let cookie = sessions::open(&state, &token, &device).await?;
+
+    Ok((jar.add(cookie), Redirect::to("/")).into_response())
+}
+
+/// The account linked to the identity in `claims`, creating both on the first sign in.
+/// An identity is never linked to an existing account by email alone.
+async fn account(db: &Surreal<Any>, provider: &Provider, claims: &IdClaims) -> Result<Account, Error> {
+    let name = &provider.config.name;
+
+    let linked: Option<Thing> = db.query("SELECT VALUE user FROM identity WHERE provider = $provider AND subject = $subject LIMIT 1")
+        .bind(("provider", name))
+        .bind(("subject", &claims.sub))
+        .await?
+        .take(0)?;
+
+    let user = match linked {
+        Some(user) => {
+            db.query("UPDATE identity SET last_used_at = time::now() WHERE provider = $provider AND subject = $subject")
+                .bind(("provider", name))
+                .bind(("subject", &claims.sub))
+                .await?
+                .check()?;
+
+            user
+        }
+        None => create_account(db, provider, claims).await?,
+    };
+
+    let account: Option<Account> = db.query("SELECT id, disabled, totp_enabled FROM $user")
+        .bind(("user", &user))
+        .await?
+        .take(0)?;
+
+    account.ok_or_else(|| Error::NotFound(format!("{user} linked to a {name} identity")))
+}
+
+async fn create_account(db: &Surreal<Any>, provider: &Provider, claims: &IdClaims) -> Result<Thing, Error> {
+    let Some(email) = claims.email.as_deref() else {
+        return Err(Error::Validation(format!("{} did not share an email address.", provider.label())));
+    };
+
+    let taken: Option<Thing> = db.query("SELECT VALUE id FROM user WHERE email = $email LIMIT 1")
+        .bind(("email", email))
+        .await?
+        .take(0)?;
+
+    if taken.is_some() {
+        return Err(Error::Conflict("An account with this email already exists. Sign in with your password instead.".to_string()));
+    }
+
+    let username = free_username(db, claims, email).await?;
+    let (first_name, last_name) = names(claims, &username);
+    let verified = matches!(&claims.email_verified, Some(serde_json::Value::Bool(true)))
+        || matches!(&claims.email_verified, Some(serde_json::Value::String(v)) if v == "true");
+
+    let mut res = db.query("
+        BEGIN TRANSACTION;
+        LET $user = type::thing('user', $username);
+        CREATE $user SET
+            email = $email,
+            pass = crypto::argon2::generate($password),
+            first_name = $first_name,
+            last_name = $last_name,
+            verified = $verified;
+        CREATE identity SET user = $user, provider = $provider, subject = $subject, email = $email, last_used_at = time::now();
+        RETURN $user;
+        COMMIT TRANSACTION;
+    ")
+        // Nobody knows this password; a reset link sets a real one.
+        .bind(("password", token::generate()))
+        .bind(("username", &username))
+        .bind(("email", email))
+        .bind(("first_name", first_name))
+        .bind(("last_name", last_name))
+        .bind(("verified", verified))
+        .bind(("provider", &provider.config.name))
+        .bind(("subject", &claims.sub))
+        .await?;
+
+    let user: Option<Thing> = res.take(3)?;
+
+    user.ok_or_else(|| Error::Provider("account was not created".to_string()))
+}
+
+/// A username that is not taken yet, based on what the provider knows the user as.
+async fn free_username(db: &Surreal<Any>, claims: &IdClaims, email: &str) -> Result<String, Error> {
+    let wanted = claims.preferred_username.as_deref().unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+    let mut base: String = wanted
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+        .take(24)
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    if base.len() < 3 {
+        base = "user".to_string();
+    }
+
+    for attempt in 0..5 {
+        let candidate = if attempt == 0 {
+            base.clone()
+        } else {
+            format!("{base}-{:04}", rand::thread_rng().gen_range(0..10_000))
+        };
+
+        let taken: Option<Thing> = db.query("SELECT VALUE id FROM type::thing('user', $username)")
+            .bind(("username", &candidate))
+            .await?
+            .take(0)?;
+
+        if taken.is_none() {
+            return Ok(candidate);
+        }
+    }
+
+    Err(Error::Conflict("Could not pick a username, please try again.".to_string()))
+}
+
+/// First and last name from the claims, cut to the length sign up allows.
+fn names(claims: &IdClaims, username: &str) -> (String, String) {
+    let (first, last) = match (&claims.given_name, &claims.family_name, &claims.name) {
+        (Some(first), last, _) => (first.clone(), last.clone().unwrap_or_default()),
+        (None, _, Some(name)) => name
+            .split_once(' ')
+            .map_or_else(|| (name.clone(), String::new()), |(first, last)| (first.to_string(), last.to_string())),
+        (None, _, None) => (username.to_string(), String::new()),
+    };
+
+    let first = if first.trim().is_empty() { username.to_string() } else { first };
+
+    (first.trim().chars().take(64).collect(), last.trim().chars().take(64).collect())
+}
@@ -1,10 +1,23 @@
-use deadpool::managed::{self, Pool};
-use deadpool::async_trait;
-use surrealdb::{Surreal, engine::remote::ws::{Client, Ws}};
+use std::time::Duration;
+use deadpool::managed::{self, Pool, RecycleError};
+use deadpool::{async_trait, Runtime};
+use surrealdb::{Surreal, engine::any::{self, Any}, opt::auth::Root};
+use tokio::sync::OnceCell;
+use crate::config::SurrealConfig;
 
-#[derive(Debug)]
 pub struct Manager {
-    url: String
+    url: String,
+    /// Embedded engines (`mem://`, `rocksdb://`) own the datastore, so every pooled object
+    /// is a handle to this single instance instead of a new connection.
+    embedded: Option<OnceCell<Surreal<Any>>>,
+    namespace: String,
+    database: String,
+    /// Root credentials for schema changes; regular requests sign in through the scope.
+    root: Option<(String, String)>,
+    /// Whether connections are signed in as root and kept that way, see [`Privileged`].
+    privileged: bool,
+    max_age: Duration,
+    idle_timeout: Duration,
 }
 
 pub type SurrealManager = Pool<Manager>;
@@ -12,37 +25,204 @@ pub type SurrealConnection = managed::Object<Manager>;
 
 impl Manager {
     /// Create a new `Manager` that handles creating and recyling connections from a 
-    /// pool to a `SurrealDB` instance.
+    /// pool to a `SurrealDB` instance. The URL scheme selects the engine: `ws://`, `wss://`,
+    /// `http://`, `https://`, `mem://` or `rocksdb://path`; a bare `host:port` means `ws://`.
+    ///
+    /// Embedded engines keep a single session, so their pool is capped at one connection.
     ///
     /// # Panics
     ///
     /// Panics if the runtime cannot be initialized.
     #[must_use]
-    pub fn new(url: &str, size: usize) -> managed::Pool<Manager> {
-        Pool::builder(Manager { url: url.to_string() })
+    pub fn new(config: &SurrealConfig) -> managed::Pool<Manager> {
+        let url = endpoint(&config.url);
+        let embedded = is_embedded(&url);
+        let size = if embedded { 1 } else { config.pool_size };
+
+        if embedded {
+            tracing::info!(%url, "Using embedded SurrealDB engine with a single shared connection");
+        }
+
+        Self::build(config, url, embedded.then(OnceCell::new), false, size)
+    }
+
+    fn build(config: &SurrealConfig, url: String, embedded: Option<OnceCell<Surreal<Any>>>, privileged: bool, size: usize) -> managed::Pool<Manager> {
+        Pool::builder(Manager {
+            url,
+            embedded,
+            namespace: config.namespace.clone(),
+            database: config.database.clone(),
+            root: config.username.clone().zip(config.password.clone()),
+            privileged,
+            max_age: Duration::from_secs(config.max_age_secs),
+            idle_timeout: Duration::from_secs(config.idle_timeout_secs),
+        })
             .max_size(size)
+            .wait_timeout(Some(Duration::from_secs(config.wait_timeout_secs)))
+            .create_timeout(Some(Duration::from_secs(config.create_timeout_secs)))
+            .recycle_timeout(Some(Duration::from_secs(config.recycle_timeout_secs)))
+            .runtime(Runtime::Tokio1)
             .build()
             .expect("No runtime (tokio/async-std) specified")
     }
+
+    /// Whether a connection with these metrics is past its maximum age or idle timeout.
+    fn is_stale(&self, metrics: &managed::Metrics) -> bool {
+        metrics.age() >= self.max_age || metrics.last_used() >= self.idle_timeout
+    }
+}
+
+/// Most root connections kept open at once on remote engines; root work is rare next to
+/// regular requests.
+const PRIVILEGED_POOL_SIZE: usize = 4;
+
+/// Root access to the database, for work scoped sessions are not allowed to do such as
+/// migrations or reading tokens stored with `PERMISSIONS NONE`.
+///
+/// Remote engines get a small pool of their own whose connections stay signed in with the
+/// configured root credentials. Embedded engines run without authentication and have a single
+/// session, so root work checks it out of the request pool like a request would: checkout
+/// drops the previous scoped session and nobody else can use the handle until it is returned.
+///
+/// Hold a root connection as briefly as possible and never together with another pooled one,
+/// or requests on an embedded engine wait on each other.
+#[derive(Debug, Clone)]
+pub struct Privileged {
+    /// `None` on remote engines without root credentials.
+    pool: Option<SurrealManager>,
+}
+
+impl Privileged {
+    /// Root access for the engine in `config`, sharing `pool` on embedded engines.
+    #[must_use]
+    pub fn new(config: &SurrealConfig, pool: &SurrealManager) -> Self {
+        let url = endpoint(&config.url);
+
+        let pool = if is_embedded(&url) {
+            Some(pool.clone())
+        } else if config.username.is_some() && config.password.is_some() {
+            Some(Manager::build(config, url, None, true, config.pool_size.min(PRIVILEGED_POOL_SIZE)))
+        } else {
+            None
+        };
+
+        Self { pool }
+    }
+
+    /// Check out a root connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no root credentials are configured for a remote engine, or no
+    /// connection could be obtained.
+    pub async fn get(&self) -> Result<SurrealConnection, crate::error::Error> {
+        let Some(pool) = &self.pool else {
+            return Err(crate::error::Error::Forbidden("root access needs surreal.username and surreal.password".to_string()));
+        };
+
+        Ok(pool.get().await?)
+    }
+}
+
+/// Normalize a configured URL, defaulting to `ws://` when no scheme is given.
+#[must_use]
+pub fn endpoint(url: &str) -> String {
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("ws://{url}")
+    }
+}
+
+/// Whether the URL selects an engine running inside this process.
+#[must_use]
+pub fn is_embedded(url: &str) -> bool {
+    !matches!(url.split_once("://").map(|(scheme, _)| scheme), Some("ws" | "wss" | "http" | "https"))
+}
+
+impl std::fmt::Debug for Manager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Manager")
+            .field("url", &self.url)
+            .field("embedded", &self.embedded.is_some())
+            .field("namespace", &self.namespace)
+            .field("database", &self.database)
+            .field("root", &self.root.as_ref().map(|(username, _)| username))
+            .field("privileged", &self.privileged)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Remove `conn` from the pool if `error` shows its websocket is broken, so it is dropped
+/// instead of being recycled and handed to the next request.
+pub fn discard_if_broken(conn: SurrealConnection, error: &crate::error::Error) {
+    if error.is_connection_error() {
+        tracing::warn!(%error, "Discarding broken SurrealDB connection");
+        drop(managed::Object::take(conn));
+    }
+}
+
+/// Periodically evict connections that are past their maximum age or idle timeout, so
+/// idle connections are not kept open until the next checkout.
+pub async fn reap(pool: SurrealManager, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let before = pool.status().size;
+        pool.retain(|_, metrics| !pool.manager().is_stale(&metrics));
+        let evicted = before.saturating_sub(pool.status().size);
+
+        if evicted > 0 {
+            tracing::debug!(evicted, "Reaped stale SurrealDB connections");
+        }
+    }
 }
 
 #[async_trait]
 impl managed::Manager for Manager {
     type Error = crate::error::Error;
-    type Type = Surreal<Client>;
+    type Type = Surreal<Any>;
 
+    #[tracing::instrument(name = "pool.create", skip(self), fields(url = %self.url), err)]
     async fn create(&self) ->  Result<Self::Type, Self::Error> {
-        let db = Surreal::new::<Ws>(self.url.as_str()).await?;
+        let db = match &self.embedded {
+            Some(shared) => shared.get_or_try_init(|| async { any::connect(self.url.as_str()).await }).await?.clone(),
+            None => any::connect(self.url.as_str()).await?,
+        };
+
+        // Privileged pools are only built with root credentials, see `Privileged::new`.
+        if let (true, Some((username, password))) = (self.privileged, &self.root) {
+            db.signin(Root { username, password }).await?;
+        } else if self.embedded.is_some() {
+            // An object dropped from the pool leaves its session on the shared handle.
+            db.invalidate().await?;
+        }
 
-        db.use_ns("demo").use_db("demo").await?;
+        db.use_ns(&self.namespace).use_db(&self.database).await?;
 
         Ok(db)
     }
 
-    async fn recycle(&self, conn: &mut Self::Type, _: &managed::Metrics) -> managed::RecycleResult<Self::Error> {
+    #[tracing::instrument(name = "pool.recycle", skip_all)]
+    async fn recycle(&self, conn: &mut Self::Type, metrics: &managed::Metrics) -> managed::RecycleResult<Self::Error> {
+        if self.is_stale(metrics) {
+            return Err(RecycleError::StaticMessage("connection exceeded its maximum age or idle timeout"));
+        }
+
+        // Root connections keep their sign in; request connections drop the previous user.
+        if !self.privileged {
+            conn.invalidate().await.map_err(Self::Error::from)?;
+        }
+
+        conn.use_ns(&self.namespace).use_db(&self.database).await.map_err(Self::Error::from)?;
 
-        conn.invalidate().await.map_err(Self::Error::from)?;
-        conn.use_ns("demo").use_db("demo").await.map_err(Self::Error::from)?;
+        // A dead websocket would otherwise only fail on the first query of the next user.
+        conn.query("RETURN true").await
+            .and_then(surrealdb::Response::check)
+            .map_err(|e| Self::Error::from(e).into_recycle_error())?;
 
         Ok(())
     }
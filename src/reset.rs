@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use axum::{extract::{Path, State}, response::{IntoResponse, Response}, Form};
+use http::StatusCode;
+use maud::{html, Markup};
+use surrealdb::{Surreal, engine::any::Any, sql::Thing};
+
+use crate::{error::Error, state::Context, template::Template, token, validation::{self, FieldErrors, RESET_FIELDS}};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ForgotInfo {
+    email: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ResetInfo {
+    password: String,
+    confirm: String,
+}
+
+/// Create a reset token for the account registered with `email`, valid for `ttl`.
+/// Returns `None` when no account uses that email.
+///
+/// # Errors
+///
+/// Returns an error if the database query fails.
+pub async fn create(db: &Surreal<Any>, email: &str, ttl: Duration) -> Result<Option<String>, Error> {
+    let user: Option<Thing> = db.query("SELECT VALUE id FROM user WHERE email = $email LIMIT 1")
+        .bind(("email", email))
+        .await?
+        .take(0)?;
+
+    let Some(user) = user else {
+        return Ok(None);
+    };
+
+    let token = token::generate();
+
+    db.query("CREATE password_reset SET user = $user, token_hash = $hash, expires_at = time::now() + $ttl")
+        .bind(("user", user))
+        .bind(("hash", token::hash(&token)))
+        .bind(("ttl", surrealdb::sql::Duration::from(ttl)))
+        .await?
+        .check()?;
+
+    Ok(Some(token))
+}
+
+/// Set a new password with a reset token, returning the user it belongs to. The token and
+/// every other outstanding token of the user are used up, and every session of the user is
+/// closed.
+///
+/// # Errors
+///
+/// Returns `Error::Validation` if the token is unknown, used or expired.
+pub async fn consume(db: &Surreal<Any>, token: &str, password: &str) -> Result<Thing, Error> {
+    let res = db.query("
+        BEGIN TRANSACTION;
+        LET $reset = (SELECT * FROM password_reset WHERE token_hash = $hash AND used_at = NONE AND expires_at > time::now())[0];
+        IF !$reset THEN
+            THROW 'invalid reset token'
+        END;
+        UPDATE password_reset SET used_at = time::now() WHERE user = $reset.user AND used_at = NONE;
+        UPDATE $reset.user SET pass = crypto::argon2::generate($password), pass_changed_at = time::now();
+        DELETE session WHERE user = $reset.user;
+        COMMIT TRANSACTION;
+        SELECT VALUE user FROM password_reset WHERE token_hash = $hash;
+    ")
+        .bind(("hash", token::hash(token)))
+        .bind(("password", password))
+        .await?
+        .check();
+
+    // LET, IF, the two UPDATEs and DELETE come before the SELECT.
+    match res.map(|mut res| res.take::<Option<Thing>>(5)) {
+        Ok(Ok(Some(user))) => Ok(user),
+        Ok(Ok(None)) => Err(Error::Validation("This reset link is invalid or has expired.".to_string())),
+        Ok(Err(e)) => Err(e.into()),
+        Err(e) if crate::error::is_connection_error(&e) => Err(e.into()),
+        Err(e) => {
+            tracing::info!(error = %e, "Password reset rejected");
+            Err(Error::Validation("This reset link is invalid or has expired.".to_string()))
+        }
+    }
+}
+
+pub async fn forgot(b: Template) -> Markup {
+    b.render(html!{
+        div.flex.flex-col.justify-center.h-screen {
+            div."flex flex-col items-center" hx-ext="response-targets" {
+                form."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
+                    h1."text-4xl".font-bold {
+                        "Forgot password"
+                    }
+                    p."text-sm max-w-xs text-center" {
+                        "Enter the email of your account and we will send you a link to choose a new password."
+                    }
+                    div #err {}
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="email" type="email" placeholder="Email" {}
+                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".w-full
+                    hx-post="/auth/forgot" hx-target="#err" "hx-target-error"="#err"
+                    {
+                        "Send reset link"
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Email a reset link if an account uses the submitted email. The response is the same
+/// either way so the form cannot be used to find out who has an account.
+#[tracing::instrument(skip_all)]
+pub async fn request_reset(State(state): State<Context>, Form(info): Form<ForgotInfo>) -> Result<Markup, Error> {
+    let Some(mailer) = state.mailer.clone() else {
+        return Err(Error::Unavailable("password reset needs mail to be enabled".to_string()));
+    };
+
+    let db = state.privileged().await?;
+
+    if let Some(token) = create(&db, info.email.trim(), state.reset_token_ttl).await? {
+        let link = format!("{}/auth/reset/{token}", state.base_url);
+        let minutes = state.reset_token_ttl.as_secs() / 60;
+        let body = format!(
+            "Someone asked to reset the password of your Stickers account.\n\n\
+             Open this link within {minutes} minutes to choose a new password:\n{link}\n\n\
+             If it was not you, you can ignore this email.\n"
+        );
+
+        // Sent in the background so the response time does not reveal whether the account exists.
+        tokio::spawn(async move {
+            if let Err(e) = mailer.send(info.email.trim(), "Reset your Stickers password", body).await {
+                tracing::error!(error = %e, "Failed to send password reset email");
+            }
+        });
+    }
+
+    Ok(html! {
+        div ."bg-green-100 border border-green-400 text-green-700 px-4 py-2 rounded relative" role="status" {
+            "If an account uses that email, a reset link is on its way."
+        }
+    })
+}
+
+pub async fn reset_form(b: Template, Path(token): Path<String>) -> Markup {
+    b.render(html!{
+        div.flex.flex-col.justify-center.h-screen {
+            div."flex flex-col items-center" hx-ext="response-targets" {
+                form."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
+                    h1."text-4xl".font-bold {
+                        "Choose a new password"
+                    }
+                    div #err {}
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="password" type="password" placeholder="New password" {}
+                    (validation::field_error("password", None, false))
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="confirm" type="password" placeholder="Repeat password" {}
+                    (validation::field_error("confirm", None, false))
+                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".w-full
+                    hx-post=(format!("/auth/reset/{token}")) "hx-target-error"="#err"
+                    {
+                        "Set password"
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn perform_reset(State(state): State<Context>, Path(token): Path<String>, Form(info): Form<ResetInfo>) -> Result<Response, Error> {
+    let mut errors = FieldErrors::new(RESET_FIELDS);
+
+    validation::password(&mut errors, &info.password, "");
+    if info.password != info.confirm {
+        errors.add("confirm", "Passwords do not match.");
+    }
+
+    if !errors.is_empty() {
+        return Ok(errors.into_response());
+    }
+
+    let db = state.privileged().await?;
+    let user = consume(&db, &token, &info.password).await?;
+    state.session_cache.forget_user(&user);
+
+    let (mut parts, body) = StatusCode::OK.into_response().into_parts();
+    parts.headers.insert("HX-Redirect", "/auth/signin".parse().expect("Infallible"));
+
+    Ok(Response::from_parts(parts, body))
+}
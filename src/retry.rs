@@ -0,0 +1,148 @@
+use std::{future::Future, sync::{Mutex, atomic::{AtomicU32, Ordering}}, time::{Duration, Instant}};
+
+use rand::Rng;
+
+use crate::{config::SurrealConfig, error::Error};
+
+/// Exponential backoff with full jitter for transient database failures.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    #[must_use]
+    pub fn new(config: &SurrealConfig) -> Self {
+        Self {
+            attempts: config.retry_attempts.max(1),
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Fast fails database work while SurrealDB is down instead of letting every request wait
+/// for its own timeouts.
+///
+/// The breaker opens after `threshold` consecutive transient failures and stays open for
+/// `cooldown`. After that exactly one trial is let through while every other call is still
+/// rejected: success closes the breaker, failure opens it again. A trial that never reports
+/// back, because its request was cancelled, is replaced by a new one after another `cooldown`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: AtomicU32,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    /// A trial call is in flight since `since`.
+    HalfOpen { since: Instant },
+}
+
+impl CircuitBreaker {
+    #[must_use]
+    pub fn new(config: &SurrealConfig) -> Self {
+        Self {
+            threshold: config.breaker_threshold.max(1),
+            cooldown: Duration::from_secs(config.breaker_cooldown_secs),
+            failures: AtomicU32::new(0),
+            state: Mutex::new(BreakerState::Closed),
+        }
+    }
+
+    /// Whether calls should currently be rejected without trying. Once the cooldown is over
+    /// the first caller gets `false` and becomes the trial, so it must report back through
+    /// [`retry`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the internal lock is poisoned.
+    #[must_use]
+    pub fn is_open(&self) -> bool {
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+        let now = Instant::now();
+
+        match *state {
+            BreakerState::Closed => false,
+            BreakerState::Open { until } if now < until => true,
+            BreakerState::HalfOpen { since } if now < since + self.cooldown => true,
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => {
+                *state = BreakerState::HalfOpen { since: now };
+                false
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+        *self.state.lock().expect("Circuit breaker lock poisoned") = BreakerState::Closed;
+    }
+
+    fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+
+        if failures >= self.threshold || matches!(*state, BreakerState::HalfOpen { .. }) {
+            tracing::warn!(failures, cooldown = ?self.cooldown, "Database circuit breaker opened");
+            *state = BreakerState::Open { until: Instant::now() + self.cooldown };
+        }
+    }
+}
+
+/// Run `op`, retrying transient failures according to `policy`. Only use this for idempotent
+/// operations such as pool checkout and reads.
+///
+/// # Errors
+///
+/// Returns `Error::Unavailable` while the circuit is open, otherwise the last error of `op`.
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, breaker: &CircuitBreaker, mut op: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    if breaker.is_open() {
+        return Err(Error::Unavailable("database circuit breaker is open".to_string()));
+    }
+
+    let mut attempt = 0;
+
+    loop {
+        match op().await {
+            Ok(value) => {
+                breaker.record_success();
+                return Ok(value);
+            }
+            Err(e) if e.is_transient() => {
+                attempt += 1;
+
+                if attempt >= policy.attempts {
+                    breaker.record_failure();
+                    return Err(e);
+                }
+
+                let delay = policy.delay(attempt);
+                tracing::debug!(error = %e, attempt, ?delay, "Retrying transient database failure");
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                breaker.record_success();
+                return Err(e);
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use surrealdb::sql::Thing;
+
+use crate::{auth::Session, token};
+
+/// Sessions resolved recently, so requests in quick succession skip the database. Entries
+/// are keyed by a hash of the session cookie, expire after `ttl` and the oldest go first once
+/// `capacity` is reached.
+///
+/// Sign outs, revocations and account changes made through the app are forgotten right
+/// away. Changes made elsewhere, like with the CLI or on another instance, are not seen until
+/// the entries expire, so keep `ttl` short.
+pub struct SessionCache {
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    sessions: HashMap<String, (Session, Instant)>,
+    /// Keys in the order they were cached, with the instant to tell them from later inserts.
+    order: VecDeque<(String, Instant)>,
+}
+
+impl SessionCache {
+    /// A cache keeping up to `capacity` sessions for `ttl`. A zero `ttl` or `capacity`
+    /// turns it off.
+    #[must_use]
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            inner: Mutex::new(Entries::default()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.capacity > 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn key(session: &str, token: &str) -> String {
+        token::hash(&format!("{session}:{token}"))
+    }
+
+    /// The cached session of the cookie carrying `session` and `token`, if still fresh.
+    #[must_use]
+    pub fn get(&self, session: &str, token: &str) -> Option<Session> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let key = Self::key(session, token);
+        let mut entries = self.lock();
+
+        let cached = entries.sessions
+            .get(&key)
+            .map(|(session, cached_at)| (cached_at.elapsed() < self.ttl).then(|| session.clone()));
+
+        match cached {
+            Some(Some(session)) => Some(session),
+            Some(None) => {
+                self.evict_expired(&mut entries);
+                None
+            },
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, session: &Session) {
+        if !self.enabled() {
+            return;
+        }
+
+        let key = Self::key(session.session(), session.token());
+        let now = Instant::now();
+        let mut guard = self.lock();
+        let entries = &mut *guard;
+        self.evict_expired(entries);
+
+        entries.sessions.insert(key.clone(), (session.clone(), now));
+        entries.order.push_back((key, now));
+
+        while entries.sessions.len() > self.capacity {
+            let Some((key, at)) = entries.order.pop_front() else {
+                break;
+            };
+
+            if entries.sessions.get(&key).is_some_and(|(_, cached_at)| *cached_at == at) {
+                entries.sessions.remove(&key);
+            }
+        }
+
+        // Forgotten and replaced entries leave their keys behind.
+        if entries.order.len() > 2 * self.capacity {
+            let sessions = &entries.sessions;
+            entries.order.retain(|(key, at)| sessions.get(key).is_some_and(|(_, cached_at)| cached_at == at));
+        }
+    }
+
+    /// Drop the entries older than `ttl` together with their keys. Keys are kept in the
+    /// order they were cached, so the expired ones are all at the front.
+    fn evict_expired(&self, entries: &mut Entries) {
+        while entries.order.front().is_some_and(|(_, at)| at.elapsed() >= self.ttl) {
+            let Some((key, at)) = entries.order.pop_front() else {
+                break;
+            };
+
+            if entries.sessions.get(&key).is_some_and(|(_, cached_at)| *cached_at == at) {
+                entries.sessions.remove(&key);
+            }
+        }
+    }
+
+    /// Forget the server-side session `session`, after it was closed.
+    pub fn forget_session(&self, session: &str) {
+        self.lock().sessions.retain(|_, (cached, _)| cached.session() != session);
+    }
+
+    /// Forget every session of `user`, after their account changed or they signed out everywhere.
+    pub fn forget_user(&self, user: &Thing) {
+        self.lock().sessions.retain(|_, (cached, _)| cached.id() != user);
+    }
+}
+
+impl std::fmt::Debug for SessionCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCache")
+            .field("ttl", &self.ttl)
+            .field("capacity", &self.capacity)
+            .field("len", &self.lock().sessions.len())
+            .finish()
+    }
+}
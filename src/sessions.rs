@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+
+use axum::{async_trait, extract::{ConnectInfo, FromRequestParts, Path, State}, response::{IntoResponse, Response}};
+use axum_extra::extract::{PrivateCookieJar, cookie::Cookie};
+use http::{header::USER_AGENT, request::Parts, StatusCode};
+use maud::{html, Markup};
+use surrealdb::sql::{Datetime, Thing};
+
+use crate::{auth::Session, error::Error, pool::SurrealConnection, state::{Context, State as AppState}, template::Template};
+
+/// Longest user agent kept on a session; the rest is cut off.
+const MAX_USER_AGENT: usize = 512;
+
+/// Where a sign in comes from, recorded on the session it opens.
+#[derive(Debug, Clone, Default)]
+pub struct Device {
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Device {
+    /// Short description of the browser and system, like "Firefox on Linux".
+    #[must_use]
+    pub fn describe(&self) -> String {
+        const BROWSERS: [(&str, &str); 5] = [("Edg/", "Edge"), ("OPR/", "Opera"), ("Firefox/", "Firefox"), ("Chrome/", "Chrome"), ("Safari/", "Safari")];
+        const SYSTEMS: [(&str, &str); 7] = [("Android", "Android"), ("iPhone", "iOS"), ("iPad", "iOS"), ("Windows", "Windows"), ("Mac OS X", "macOS"), ("CrOS", "ChromeOS"), ("Linux", "Linux")];
+
+        let Some(user_agent) = &self.user_agent else {
+            return "Unknown device".to_string();
+        };
+
+        let find = |names: &[(&str, &'static str)]| names.iter().find(|(marker, _)| user_agent.contains(marker)).map(|(_, name)| *name);
+
+        match (find(&BROWSERS), find(&SYSTEMS)) {
+            (Some(browser), Some(system)) => format!("{browser} on {system}"),
+            (Some(name), None) | (None, Some(name)) => name.to_string(),
+            (None, None) => "Unknown device".to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Device {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let ip = parts.extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        let user_agent = parts.headers
+            .get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(|agent| agent.chars().take(MAX_USER_AGENT).collect());
+
+        Ok(Self { ip, user_agent })
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct Record {
+    id: Thing,
+    device: String,
+    ip: Option<String>,
+    created_at: Datetime,
+    last_seen_at: Datetime,
+}
+
+/// A pooled connection signed in as the user of `token`.
+async fn connect(state: &AppState, token: &str) -> Result<SurrealConnection, Error> {
+    let db = state.db().await?;
+
+    db.authenticate(token).await.map_err(|e| {
+        if crate::error::is_connection_error(&e) {
+            Error::Database(e)
+        } else {
+            Error::AuthFailed(e.to_string())
+        }
+    })?;
+
+    Ok(db)
+}
+
+/// Open a server-side session for the user of `token` and build the cookie carrying both.
+///
+/// # Errors
+///
+/// Returns an error if the token is rejected or the database is unreachable.
+pub async fn open(state: &AppState, token: &str, device: &Device) -> Result<Cookie<'static>, Error> {
+    let db = connect(state, token).await?;
+
+    let session: Option<Thing> = db.query("CREATE session SET user = $auth.id, device = $device, ip = $ip, user_agent = $user_agent RETURN VALUE id")
+        .bind(("device", device.describe()))
+        .bind(("ip", &device.ip))
+        .bind(("user_agent", &device.user_agent))
+        .await?
+        .take(0)?;
+
+    let session = session.ok_or_else(|| Error::AuthFailed("could not open a session".to_string()))?;
+
+    Ok(state.session_cookie(&session.id.to_raw(), token))
+}
+
+/// Close the session `session` was extracted from, so its token stops being accepted.
+///
+/// # Errors
+///
+/// Returns an error if the database is unreachable.
+pub async fn close(state: &AppState, session: &Session) -> Result<(), Error> {
+    let db = connect(state, session.token()).await?;
+
+    db.query("DELETE type::thing('session', $session) WHERE user = $auth.id")
+        .bind(("session", session.session()))
+        .await?
+        .check()?;
+
+    state.session_cache.forget_session(session.session());
+
+    Ok(())
+}
+
+fn format_time(time: &Datetime) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+/// Every open session of the signed in user, most recently seen first.
+pub async fn list(State(state): State<Context>, b: Template, session: Session) -> Result<Markup, Error> {
+    let db = connect(&state, session.token()).await?;
+
+    let records: Vec<Record> = db.query("SELECT id, device, ip, created_at, last_seen_at FROM session WHERE user = $auth.id ORDER BY last_seen_at DESC")
+        .await?
+        .take(0)?;
+
+    Ok(b.render(html!{
+        div.flex.flex-col.items-center."p-8" hx-ext="response-targets" {
+            div ."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
+                h1."text-4xl".font-bold { "Your sessions" }
+                div #err {}
+                ul #sessions ."flex flex-col space-y-4" {
+                    @for record in &records {
+                        @let key = record.id.id.to_raw();
+                        li ."flex flex-row items-center justify-between space-x-8" {
+                            div ."flex flex-col" {
+                                span.font-bold { (record.device) }
+                                span."text-sm" {
+                                    (record.ip.as_deref().unwrap_or("Unknown address"))
+                                    " · signed in " (format_time(&record.created_at))
+                                    " · last seen " (format_time(&record.last_seen_at))
+                                }
+                            }
+                            @if key == session.session() {
+                                span."text-sm" { "This device" }
+                            } @else {
+                                button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2"
+                                hx-post=(format!("/account/sessions/{key}/revoke")) hx-target="closest li" hx-swap="outerHTML" "hx-target-error"="#err"
+                                {
+                                    "Revoke"
+                                }
+                            }
+                        }
+                    }
+                }
+                button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2"
+                hx-post="/account/sessions/revoke-all" "hx-target-error"="#err"
+                {
+                    "Sign out everywhere"
+                }
+            }
+        }
+    }))
+}
+
+/// Close another session of the signed in user; the current one is closed by signing out.
+#[tracing::instrument(skip_all, fields(user = %session.id()))]
+pub async fn revoke(State(state): State<Context>, session: Session, Path(key): Path<String>) -> Result<Markup, Error> {
+    if key == session.session() {
+        return Err(Error::Validation("Sign out to end the session on this device.".to_string()));
+    }
+
+    let db = connect(&state, session.token()).await?;
+
+    let revoked: Option<Record> = db.query("DELETE type::thing('session', $session) WHERE user = $auth.id RETURN BEFORE")
+        .bind(("session", &key))
+        .await?
+        .take(0)?;
+
+    if revoked.is_none() {
+        return Err(Error::NotFound(format!("session {key}")));
+    }
+
+    state.session_cache.forget_session(&key);
+
+    tracing::info!(session = %key, "Session revoked");
+
+    Ok(html! {})
+}
+
+/// Close every session of the signed in user, this one included.
+#[tracing::instrument(skip_all, fields(user = %session.id()))]
+pub async fn revoke_all(State(state): State<Context>, jar: PrivateCookieJar, session: Session) -> Result<Response, Error> {
+    let db = connect(&state, session.token()).await?;
+
+    db.query("DELETE session WHERE user = $auth.id")
+        .await?
+        .check()?;
+
+    state.session_cache.forget_user(session.id());
+
+    tracing::info!("Signed out everywhere");
+
+    let (mut parts, body) = (jar.remove(state.removal_cookie()), StatusCode::OK).into_response().into_parts();
+    parts.headers.insert("HX-Redirect", "/".parse().expect("Infallible"));
+
+    Ok(Response::from_parts(parts, body))
+}
@@ -0,0 +1,78 @@
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration};
+
+use tokio::sync::watch;
+
+/// Shared shutdown signal. Every clone resolves once shutdown has been requested.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    requested: watch::Receiver<bool>,
+    draining: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Create a signal that fires on `SIGINT` (Ctrl+C) or `SIGTERM`.
+    ///
+    /// The instance is marked as draining as soon as the signal arrives so readiness checks
+    /// fail, and servers are only asked to stop after `grace` so load balancers have time to
+    /// stop routing new requests here.
+    #[must_use]
+    pub fn from_os_signals(grace: Duration) -> Self {
+        let (tx, rx) = watch::channel(false);
+        let draining = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn({
+            let draining = draining.clone();
+            async move {
+                os_signal().await;
+                draining.store(true, Ordering::SeqCst);
+                tracing::info!(?grace, "Shutdown requested, reporting not ready");
+
+                tokio::time::sleep(grace).await;
+                tracing::info!("No longer accepting connections");
+                let _ = tx.send(true);
+            }
+        });
+
+        Self { requested: rx, draining }
+    }
+
+    /// Whether a shutdown signal has been received; readiness should fail from then on.
+    #[must_use]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once shutdown has been requested.
+    pub async fn requested(mut self) {
+        // An error means the sender is gone, which only happens once the signal already fired.
+        let _ = self.requested.wait_for(|requested| *requested).await;
+    }
+
+    /// Resolve once shutdown has been requested and `deadline` has passed, to bound draining.
+    pub async fn deadline(self, deadline: Duration) {
+        self.requested().await;
+        tokio::time::sleep(deadline).await;
+    }
+}
+
+async fn os_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
+    }
+}
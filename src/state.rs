@@ -1,30 +1,200 @@
 use core::ops::Deref;
 use axum::extract::FromRef;
-use axum_extra::extract::cookie::Key;
-use std::sync::Arc;
-use crate::pool::SurrealManager;
+use axum_extra::extract::{PrivateCookieJar, cookie::{Cookie, Key}};
+use http::HeaderMap;
+use surrealdb::opt::auth::Scope;
+use std::{sync::Arc, time::Duration};
+use crate::config::{AppConfig, CookieConfig, ImgServerConfig, ServeMode};
+use crate::error::Error;
+use crate::mail::{Mailer, SmtpMailer};
+use crate::oidc;
+use crate::pool::{Privileged, SurrealConnection, SurrealManager};
+use crate::retry::{self, CircuitBreaker, RetryPolicy};
+use crate::session_cache::SessionCache;
+use crate::shutdown::Shutdown;
 
 #[derive(Debug, Clone)]
 pub struct State {
     pub surreal: SurrealManager,
-    pub img_server: String,
-    key: Key
+    /// Root connections for data scoped sessions cannot read, like emailed tokens.
+    pub root: Privileged,
+    pub img_server: ImgServerConfig,
+    pub cookie: CookieConfig,
+    pub namespace: String,
+    pub database: String,
+    pub scope: String,
+    /// Whether the app is served over TLS; controls the `Secure` cookie flag and HSTS.
+    pub secure: bool,
+    pub shutdown: Shutdown,
+    pub retry: RetryPolicy,
+    pub breaker: Arc<CircuitBreaker>,
+    /// Set when mail is enabled; password resets and verification are unavailable otherwise.
+    pub mailer: Option<Arc<dyn Mailer>>,
+    /// Public address of the site, for links in emails.
+    pub base_url: String,
+    pub reset_token_ttl: Duration,
+    pub verify_token_ttl: Duration,
+    pub verify_resend_cooldown: Duration,
+    /// Whether sessions of unverified accounts are restricted.
+    pub restrict_unverified: bool,
+    /// Whether two-factor authentication can be used; its secrets need root access.
+    pub two_factor: bool,
+    pub totp_issuer: String,
+    pub two_factor_pending_ttl: Duration,
+    pub require_admin_two_factor: bool,
+    pub oidc: Arc<oidc::Providers>,
+    /// Key minted session tokens are signed with; sessions are only renewed when it is set.
+    pub token_secret: Option<String>,
+    /// Tokens expiring within this window are renewed when seen.
+    pub renew_within: Duration,
+    pub max_session_age: Duration,
+    pub session_cache: Arc<SessionCache>,
+    key: Key,
+    previous_keys: Vec<Key>,
 }
 
 #[derive(Clone)]
 pub struct Context(Arc<State>);
 
+/// Contents of the session cookie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionCookie {
+    /// Key of the server-side `session` record.
+    pub session: String,
+    /// SurrealDB token of the user.
+    pub token: String,
+}
+
 impl Context {
+    /// Create the shared application state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cookie keys or mail settings are invalid, which `AppConfig::load` already
+    /// rejects.
     #[must_use]
-    pub fn new(surreal: SurrealManager, img_server: &str) -> Self {
+    pub fn new(surreal: SurrealManager, root: Privileged, config: &AppConfig, shutdown: Shutdown) -> Self {
+        let mailer = config.mail.enabled.then(|| {
+            Arc::new(SmtpMailer::new(&config.mail).expect("Mail settings validated by AppConfig::load")) as Arc<dyn Mailer>
+        });
+
+        Self::with_mailer(surreal, root, config, shutdown, mailer)
+    }
+
+    /// Create the shared application state sending email through `mailer` instead of the
+    /// configured SMTP server.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cookie keys are invalid, which `AppConfig::load` already rejects, or if
+    /// the HTTP client for OIDC providers cannot be created.
+    #[must_use]
+    pub fn with_mailer(surreal: SurrealManager, root: Privileged, config: &AppConfig, shutdown: Shutdown, mailer: Option<Arc<dyn Mailer>>) -> Self {
+        let (key, previous_keys) = config.cookie.keys().expect("Cookie keys validated by AppConfig::load");
+
         Self(Arc::new(State {
-            img_server: img_server.to_string(),
+            img_server: config.img_server.clone(),
+            cookie: config.cookie.clone(),
+            namespace: config.surreal.namespace.clone(),
+            database: config.surreal.database.clone(),
+            scope: config.surreal.scope.clone(),
+            secure: config.server.mode == ServeMode::Tls,
+            shutdown,
+            retry: RetryPolicy::new(&config.surreal),
+            breaker: Arc::new(CircuitBreaker::new(&config.surreal)),
+            mailer,
+            base_url: config.mail.base_url.trim_end_matches('/').to_string(),
+            reset_token_ttl: Duration::from_secs(config.auth.reset_token_ttl_secs),
+            verify_token_ttl: Duration::from_secs(config.auth.verify_token_ttl_secs),
+            verify_resend_cooldown: Duration::from_secs(config.auth.verify_resend_cooldown_secs),
+            restrict_unverified: config.auth.restrict_unverified,
+            two_factor: config.surreal.username.is_some() || crate::pool::is_embedded(&crate::pool::endpoint(&config.surreal.url)),
+            totp_issuer: config.auth.totp_issuer.clone(),
+            two_factor_pending_ttl: Duration::from_secs(config.auth.two_factor_pending_secs),
+            require_admin_two_factor: config.auth.require_admin_two_factor,
+            oidc: Arc::new(oidc::Providers::new(&config.oidc)),
+            token_secret: config.auth.token_secret.clone(),
+            renew_within: Duration::from_secs(config.auth.renew_within_secs),
+            max_session_age: Duration::from_secs(config.auth.max_session_age_secs),
+            session_cache: Arc::new(SessionCache::new(Duration::from_secs(config.auth.session_cache_ttl_secs), config.auth.session_cache_capacity)),
             surreal,
-            key: Key::generate()
+            root,
+            key,
+            previous_keys,
         }))
     }
 }
 
+impl State {
+    /// Check out a pooled connection, retrying while the database is briefly unreachable and
+    /// fast failing with 503 while the circuit breaker is open.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no connection could be obtained.
+    pub async fn db(&self) -> Result<SurrealConnection, Error> {
+        retry::retry(&self.retry, &self.breaker, || async { Ok(self.surreal.get().await?) }).await
+    }
+
+    /// Check out a root connection, see [`Privileged`], retrying and fast failing like [`State::db`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no connection could be obtained.
+    pub async fn privileged(&self) -> Result<SurrealConnection, Error> {
+        retry::retry(&self.retry, &self.breaker, || self.root.get()).await
+    }
+
+    /// Credentials for signing in or up through the configured namespace, database and scope.
+    #[must_use]
+    pub fn scope<P>(&self, params: P) -> Scope<'_, P> {
+        Scope {
+            namespace: &self.namespace,
+            database: &self.database,
+            scope: &self.scope,
+            params,
+        }
+    }
+
+    /// Build the cookie that carries the server-side session ID and its token, using the
+    /// configured name, path and `SameSite` policy.
+    #[must_use]
+    pub fn session_cookie(&self, session: &str, token: &str) -> Cookie<'static> {
+        Cookie::build((self.cookie.name.clone(), format!("{session}:{token}")))
+            .secure(self.secure)
+            .http_only(true)
+            .path(self.cookie.path.clone())
+            .same_site(self.cookie.same_site.into())
+            .build()
+    }
+
+    /// Decrypt the session ID and token from the request cookies, trying the current key first
+    /// and then every previous key so rotating the key does not log everybody out. Cookies
+    /// from before sessions were tracked carry no ID and are ignored.
+    #[must_use]
+    pub fn session_token(&self, headers: &HeaderMap) -> Option<SessionCookie> {
+        std::iter::once(&self.key)
+            .chain(&self.previous_keys)
+            .find_map(|key| {
+                PrivateCookieJar::from_headers(headers, key.clone())
+                    .get(&self.cookie.name)
+                    .map(|cookie| cookie.value().to_string())
+            })
+            .and_then(|value| {
+                let (session, token) = value.split_once(':')?;
+                Some(SessionCookie { session: session.to_string(), token: token.to_string() })
+            })
+    }
+
+    /// Build the cookie used to remove the session token on sign out.
+    #[must_use]
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        Cookie::build(self.cookie.name.clone())
+            .path(self.cookie.path.clone())
+            .build()
+    }
+}
+
 // deref so you can still access the inner fields easily
 impl Deref for Context {
     type Target = State;
@@ -40,4 +210,4 @@ impl FromRef<Context> for Key {
     fn from_ref(state: &Context) -> Self {
         state.0.key.clone()
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,5 @@
 use axum::{extract::FromRequestParts, RequestPartsExt, async_trait};
-use http::request::Parts;
+use http::{request::Parts, StatusCode};
 use maud::{Markup, html, DOCTYPE, PreEscaped};
 use strum::{EnumIter, IntoEnumIterator};
 
@@ -94,8 +94,8 @@ impl FromRequestParts<Context> for Template {
                 title: format!("AOx0 - {}", parts.uri.path()),
                 mode: ContentMode::Full,
                 auth: Auth::from(parts.extract_with_state::<Option<Session>, Context>(state).await.map_err(|e| {
-                    println!("Auth error: {e:?}");
-                    Error::AuthFailed
+                    tracing::warn!(error = ?e, "Auth error");
+                    Error::AuthFailed(e.to_string())
                 })?),
             })
         }
@@ -157,6 +157,8 @@ fn Template(title: &str, auth: Auth, mode: ContentMode, content: Markup) -> Mark
         }
     }
 
+    let restricted = matches!(&auth, Auth::User(s) | Auth::Admin(s) if s.is_restricted());
+
     html! {
         (DOCTYPE)
         html lang="es" {
@@ -268,8 +270,8 @@ fn Template(title: &str, auth: Auth, mode: ContentMode, content: Markup) -> Mark
                                         ."text-foreground/80".text-xs
                                         .font-bold."hover:opacity-100"
                                     {
-                                        (s.first_name()[..1].to_uppercase())
-                                        (s.last_name()[..1].to_uppercase())
+                                        (initial(s.first_name()))
+                                        (initial(s.last_name()))
                                     }
                                 }
 
@@ -298,6 +300,8 @@ fn Template(title: &str, auth: Auth, mode: ContentMode, content: Markup) -> Mark
 
                                         hr."opacity-70";
                                         
+                                        (Ref("Two-factor authentication", "/account/2fa"))
+                                        (Ref("Your sessions", "/account/sessions"))
                                         (Ref("Sign out", "/signout"))
                                     }
                                 }
@@ -306,6 +310,17 @@ fn Template(title: &str, auth: Auth, mode: ContentMode, content: Markup) -> Mark
                     }
                 }
 
+                @if restricted {
+                    div."px-6 py-2 text-sm bg-yellow-100 text-yellow-800 dark:bg-yellow-900/40 dark:text-yellow-200" hx-ext="response-targets" {
+                        "Verify your email address to upload stickers. "
+                        button."underline" hx-post="/auth/verify/resend" hx-target="#verify-status" "hx-target-error"="#verify-status" {
+                            "Resend the email"
+                        }
+                        " "
+                        span #verify-status {}
+                    }
+                }
+
                 main #main { (content) }
 
                 (Footer())
@@ -314,6 +329,25 @@ fn Template(title: &str, auth: Auth, mode: ContentMode, content: Markup) -> Mark
     }
 }
 
+/// First letter of a name, upper cased; accounts from OIDC providers may have no last name.
+fn initial(name: &str) -> String {
+    name.chars().next().map(|c| c.to_uppercase().to_string()).unwrap_or_default()
+}
+
+/// Full page shown for errors on regular (non HTMX) requests.
+#[must_use]
+pub fn error_page(status: StatusCode, message: &str) -> Markup {
+    let title = format!("AOx0 - {}", status.as_u16());
+
+    Template(&title, Auth::Guest, ContentMode::Full, html! {
+        div.flex.flex-col.justify-center.items-center."h-[60vh]"."space-y-4" {
+            h1."text-4xl".font-bold { (status.as_u16()) }
+            p."text-foreground/60" { (message) }
+            (Ref("Go home", "/"))
+        }
+    })
+}
+
 #[allow(non_snake_case)]
 fn Footer() -> Markup {
     html! {
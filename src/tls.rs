@@ -0,0 +1,85 @@
+use std::{path::{Path, PathBuf}, time::{Duration, SystemTime}};
+
+use axum_server::tls_rustls::RustlsConfig;
+
+/// Keep `config` in sync with the certificate and key files on disk.
+///
+/// The pair is reloaded whenever the process receives `SIGHUP` and, if `interval` is not zero,
+/// whenever the modification time of either file changes. New connections pick up the new
+/// certificate while existing ones keep the one they were established with. A pair that fails
+/// to parse is reported and the previous certificate stays in use.
+pub async fn watch_certificates(config: RustlsConfig, cert: PathBuf, key: PathBuf, interval: Duration) {
+    let mut hangup = Hangup::new();
+    let mut last_seen = modified(&cert, &key);
+
+    let mut ticker = tokio::time::interval(interval.max(Duration::from_secs(1)));
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            () = hangup.recv() => {
+                tracing::info!(cert = %cert.display(), key = %key.display(), "TLS: SIGHUP received, reloading");
+            }
+            _ = ticker.tick(), if !interval.is_zero() => {
+                let current = modified(&cert, &key);
+                if current == last_seen {
+                    continue;
+                }
+                tracing::info!(cert = %cert.display(), key = %key.display(), "TLS: certificate files changed, reloading");
+            }
+        }
+
+        last_seen = modified(&cert, &key);
+
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => tracing::info!("TLS: certificate reloaded"),
+            Err(e) => tracing::error!(error = ?e, "TLS: failed to reload certificate, keeping the previous one"),
+        }
+    }
+}
+
+fn modified(cert: &Path, key: &Path) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(cert).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(key).and_then(|m| m.modified()).ok()?;
+
+    Some((cert, key))
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Self {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(signal) => Self(Some(signal)),
+            Err(e) => {
+                tracing::warn!(error = ?e, "TLS: cannot listen for SIGHUP, reload on signal disabled");
+                Self(None)
+            }
+        }
+    }
+
+    async fn recv(&mut self) {
+        match &mut self.0 {
+            Some(signal) => {
+                signal.recv().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Self {
+        Self
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
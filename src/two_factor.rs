@@ -0,0 +1,491 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{extract::State, response::{IntoResponse, Response}, Form};
+use axum_extra::extract::{PrivateCookieJar, cookie::{Cookie, SameSite}};
+use http::StatusCode;
+use maud::{html, Markup, PreEscaped};
+use qrcode::{QrCode, render::svg};
+use rand::seq::SliceRandom;
+use surrealdb::{Surreal, engine::any::Any, sql::{Datetime, Thing}};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{auth::Session, error::Error, sessions::{self, Device}, state::{Context, State as AppState}, template::Template, token};
+
+/// Recovery codes handed out when two-factor authentication is turned on.
+const RECOVERY_CODES: usize = 10;
+/// Characters of recovery codes, without the ones easily confused with each other.
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+/// Wrong codes in a row before every code is refused for `LOCKOUT`.
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct CodeInfo {
+    code: String,
+}
+
+/// Password accepted, second factor outstanding. Kept in a private cookie, so it cannot be
+/// read or forged by the client.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Pending {
+    user: Thing,
+    token: String,
+    expires_at: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TwoFactorStatus {
+    id: Thing,
+    #[serde(default)]
+    totp_enabled: bool,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct Secrets {
+    totp_secret: Option<String>,
+    totp_pending_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct Failures {
+    #[serde(default)]
+    totp_failures: u32,
+    totp_failed_at: Option<Datetime>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct RecoveryCode {
+    user: Thing,
+    code_hash: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn totp(state: &AppState, secret: &str, user: &Thing) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+
+    // No skew, `matching_step` tries the neighbouring steps itself to learn which one matched.
+    Some(TOTP::new_unchecked(Algorithm::SHA1, 6, 0, 30, secret, Some(state.totp_issuer.clone()), user.id.to_raw()))
+}
+
+/// The time step `code` belongs to, accepting one step of clock drift either way.
+fn matching_step(totp: &TOTP, code: &str, now: u64) -> Option<u64> {
+    [now.saturating_sub(totp.step), now, now + totp.step]
+        .into_iter()
+        .find(|&time| totp.check(code, time))
+        .map(|time| time / totp.step)
+}
+
+/// Recovery codes are entered with or without the dash and in any case.
+fn normalize(code: &str) -> String {
+    code.chars().filter(char::is_ascii_alphanumeric).collect::<String>().to_ascii_lowercase()
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let chars: String = (0..10)
+        .map(|_| char::from(*RECOVERY_ALPHABET.choose(&mut rng).expect("Alphabet is not empty")))
+        .collect();
+
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+fn unavailable() -> Error {
+    Error::Unavailable("two-factor authentication needs root access to the database".to_string())
+}
+
+/// The account signed in on `db` if it has two-factor authentication turned on.
+///
+/// # Errors
+///
+/// Returns an error if the database query fails.
+pub async fn required(db: &Surreal<Any>) -> Result<Option<Thing>, Error> {
+    let status: Option<TwoFactorStatus> = db.query("SELECT id, totp_enabled FROM $auth.id").await?.take(0)?;
+
+    Ok(status.filter(|status| status.totp_enabled).map(|status| status.id))
+}
+
+/// Check a TOTP code of `user` against their secret, or against the secret being set up
+/// when `pending`. A code is only accepted once.
+async fn check_totp(state: &AppState, db: &Surreal<Any>, user: &Thing, code: &str, pending: bool) -> Result<bool, Error> {
+    let secrets: Option<Secrets> = db.query("SELECT totp_secret, totp_pending_secret FROM $user")
+        .bind(("user", user))
+        .await?
+        .take(0)?;
+
+    let secret = secrets.and_then(|secrets| if pending { secrets.totp_pending_secret } else { secrets.totp_secret });
+    let Some(step) = secret
+        .and_then(|secret| totp(state, &secret, user))
+        .and_then(|totp| matching_step(&totp, code.trim(), now()))
+    else {
+        return Ok(false);
+    };
+
+    // Only move forward, a code seen before is refused even within its time step.
+    let accepted: Vec<Thing> = db.query("UPDATE $user SET totp_last_step = $step WHERE totp_last_step = NONE OR totp_last_step < $step RETURN VALUE id")
+        .bind(("user", user))
+        .bind(("step", step))
+        .await?
+        .take(0)?;
+
+    Ok(!accepted.is_empty())
+}
+
+/// Use up one of the recovery codes of `user`.
+async fn check_recovery_code(db: &Surreal<Any>, user: &Thing, code: &str) -> Result<bool, Error> {
+    let used: Vec<Thing> = db.query("UPDATE recovery_code SET used_at = time::now() WHERE user = $user AND code_hash = $hash AND used_at = NONE RETURN VALUE id")
+        .bind(("user", user))
+        .bind(("hash", token::hash(&normalize(code))))
+        .await?
+        .take(0)?;
+
+    Ok(!used.is_empty())
+}
+
+/// Check the second factor of `user`: a six digit TOTP code or a recovery code, or only a
+/// code of the secret being set up with `pending`. After too many wrong codes every code is
+/// refused for a while, so the six digits cannot be guessed.
+async fn check(state: &AppState, db: &Surreal<Any>, user: &Thing, code: &str, pending: bool) -> Result<bool, Error> {
+    let failures: Option<Failures> = db.query("SELECT totp_failures, totp_failed_at FROM $user")
+        .bind(("user", user))
+        .await?
+        .take(0)?;
+
+    let now = now();
+    let locked_until = failures
+        .filter(|failures| failures.totp_failures >= MAX_FAILURES)
+        .and_then(|failures| failures.totp_failed_at)
+        .map(|failed_at| u64::try_from(failed_at.timestamp()).unwrap_or_default() + LOCKOUT.as_secs());
+
+    if let Some(until) = locked_until.filter(|until| *until > now) {
+        return Err(Error::RateLimited { retry_after_secs: until - now });
+    }
+
+    let code = code.trim();
+    let ok = if code.len() == 6 && code.bytes().all(|b| b.is_ascii_digit()) {
+        check_totp(state, db, user, code, pending).await?
+    } else {
+        !pending && check_recovery_code(db, user, code).await?
+    };
+
+    let query = if ok {
+        "UPDATE $user SET totp_failures = 0, totp_failed_at = NONE"
+    } else {
+        "UPDATE $user SET totp_failures = (IF totp_failed_at > time::now() - $lockout THEN totp_failures ELSE 0 END) + 1, totp_failed_at = time::now()"
+    };
+
+    db.query(query)
+        .bind(("user", user))
+        .bind(("lockout", surrealdb::sql::Duration::from(LOCKOUT)))
+        .await?
+        .check()?;
+
+    Ok(ok)
+}
+
+/// Turn off two-factor authentication for `user` and drop their recovery codes. Returns
+/// whether the user exists.
+///
+/// # Errors
+///
+/// Returns an error if the database query fails.
+pub async fn turn_off(db: &Surreal<Any>, user: &Thing) -> Result<bool, Error> {
+    let mut res = db.query("
+        BEGIN TRANSACTION;
+        DELETE recovery_code WHERE user = $user;
+        UPDATE user SET totp_enabled = false, totp_secret = NONE, totp_pending_secret = NONE, totp_last_step = NONE, totp_failures = 0, totp_failed_at = NONE
+            WHERE id = $user RETURN VALUE id;
+        COMMIT TRANSACTION;
+    ")
+        .bind(("user", user))
+        .await?;
+
+    let updated: Vec<Thing> = res.take(1)?;
+
+    Ok(!updated.is_empty())
+}
+
+/// Replace the recovery codes of `user`, returning the new ones. Only their hashes are stored.
+async fn replace_recovery_codes(db: &Surreal<Any>, user: &Thing) -> Result<Vec<String>, Error> {
+    let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| generate_recovery_code()).collect();
+    let records: Vec<RecoveryCode> = codes
+        .iter()
+        .map(|code| RecoveryCode { user: user.clone(), code_hash: token::hash(&normalize(code)) })
+        .collect();
+
+    db.query("
+        BEGIN TRANSACTION;
+        DELETE recovery_code WHERE user = $user;
+        INSERT INTO recovery_code $codes;
+        COMMIT TRANSACTION;
+    ")
+        .bind(("user", user))
+        .bind(("codes", records))
+        .await?
+        .check()?;
+
+    Ok(codes)
+}
+
+fn pending_cookie_name(state: &AppState) -> String {
+    format!("{}_2fa", state.cookie.name)
+}
+
+fn pending_cookie(state: &AppState, value: String) -> Cookie<'static> {
+    Cookie::build((pending_cookie_name(state), value))
+        .secure(state.secure)
+        .http_only(true)
+        .path("/auth")
+        .same_site(SameSite::Strict)
+        .build()
+}
+
+/// Hold on to the session token of a user whose password was accepted until they enter
+/// their second factor at `/auth/2fa`. The caller sends the user there.
+///
+/// # Errors
+///
+/// Returns `Error::Unavailable` if two-factor authentication cannot be checked.
+///
+/// # Panics
+///
+/// This function should never panic. It panics if the pending sign in cannot be serialized.
+pub fn challenge(state: &AppState, jar: PrivateCookieJar, user: Thing, token: String) -> Result<PrivateCookieJar, Error> {
+    if !state.two_factor {
+        return Err(unavailable());
+    }
+
+    let pending = Pending {
+        user,
+        token,
+        expires_at: now() + state.two_factor_pending_ttl.as_secs(),
+    };
+    let value = serde_json::to_string(&pending).expect("Pending sign in serializes");
+
+    Ok(jar.add(pending_cookie(state, value)))
+}
+
+pub async fn challenge_form(b: Template) -> Markup {
+    b.render(html!{
+        div.flex.flex-col.justify-center.h-screen {
+            div."flex flex-col items-center" hx-ext="response-targets" {
+                form."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
+                    h1."text-4xl".font-bold {
+                        "Two-factor authentication"
+                    }
+                    p."text-sm max-w-xs text-center" {
+                        "Enter the code from your authenticator app, or one of your recovery codes."
+                    }
+                    div #err {}
+                    input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="code" type="text" autocomplete="one-time-code" placeholder="Code" {}
+                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".w-full
+                    hx-post="/auth/2fa" "hx-target-error"="#err"
+                    {
+                        "Verify"
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Finish signing in once the second factor checks out.
+#[tracing::instrument(skip_all)]
+pub async fn perform_challenge(State(state): State<Context>, jar: PrivateCookieJar, device: Device, Form(info): Form<CodeInfo>) -> Result<Response, Error> {
+    let pending = jar.get(&pending_cookie_name(&state))
+        .and_then(|cookie| serde_json::from_str::<Pending>(cookie.value()).ok())
+        .filter(|pending| pending.expires_at > now());
+
+    let Some(pending) = pending else {
+        return Err(Error::Validation("This sign in expired, please sign in again.".to_string()));
+    };
+
+    if !state.two_factor {
+        return Err(unavailable());
+    }
+
+    let db = state.privileged().await?;
+
+    if !check(&state, &db, &pending.user, &info.code, false).await? {
+        tracing::info!(user = %pending.user, "Second factor rejected");
+        return Err(Error::Validation("That code is not valid.".to_string()));
+    }
+
+    drop(db);
+    let cookie = sessions::open(&state, &pending.token, &device).await?;
+    let jar = jar
+        .remove(pending_cookie(&state, String::new()))
+        .add(cookie);
+
+    let (mut parts, body) = (jar, StatusCode::OK).into_response().into_parts();
+    parts.headers.append("HX-Redirect", "/".parse().expect("Infallible"));
+
+    Ok(Response::from_parts(parts, body))
+}
+
+fn recovery_codes(codes: &[String]) -> Markup {
+    html! {
+        div."flex flex-col items-center space-y-2" {
+            p."text-sm max-w-xs text-center" {
+                "Keep these recovery codes somewhere safe. Each one signs you in once if you lose your authenticator, and they will not be shown again."
+            }
+            ul #recovery-codes ."font-mono grid grid-cols-2 gap-x-6" {
+                @for code in codes {
+                    li { (code) }
+                }
+            }
+            a."underline" href="/account/2fa" { "Done" }
+        }
+    }
+}
+
+/// Two-factor settings of the signed in user. Starts the set up by generating a secret when
+/// it is not turned on yet and none is being set up.
+pub async fn settings(State(state): State<Context>, b: Template, session: Session) -> Result<Markup, Error> {
+    if !state.two_factor {
+        return Err(unavailable());
+    }
+
+    let content = if session.has_two_factor() {
+        html! {
+            h1."text-4xl".font-bold { "Two-factor authentication" }
+            p { "Two-factor authentication is on. Signing in asks for a code from your authenticator app." }
+            div #err {}
+            form."flex flex-col items-center space-y-4" {
+                input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="code" type="text" autocomplete="one-time-code" placeholder="Code" {}
+                div."flex flex-row space-x-4" {
+                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2"
+                    hx-post="/account/2fa/recovery" hx-target="#two-factor" "hx-target-error"="#err"
+                    {
+                        "New recovery codes"
+                    }
+                    button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2"
+                    hx-post="/account/2fa/disable" "hx-target-error"="#err"
+                    {
+                        "Turn off"
+                    }
+                }
+            }
+        }
+    } else {
+        // Reloading the page keeps the secret being set up, so an app that already scanned
+        // it still produces valid codes.
+        let db = state.privileged().await?;
+        let secret: Option<String> = db.query("UPDATE $user SET totp_pending_secret = totp_pending_secret ?? $secret RETURN VALUE totp_pending_secret")
+            .bind(("user", session.id()))
+            .bind(("secret", Secret::generate_secret().to_encoded().to_string()))
+            .await?
+            .take(0)?;
+
+        let secret = secret.ok_or_else(|| Error::NotFound(format!("user {}", session.id())))?;
+        let totp = totp(&state, &secret, session.id()).ok_or_else(unavailable)?;
+
+        // Drop the XML declaration, the SVG is inlined in the page.
+        let qr = QrCode::new(totp.get_url().as_bytes())
+            .ok()
+            .map(|qr| qr.render::<svg::Color<'_>>().min_dimensions(200, 200).build())
+            .and_then(|svg| svg.find("<svg").map(|start| svg[start..].to_string()));
+
+        html! {
+            h1."text-4xl".font-bold { "Two-factor authentication" }
+            p."text-sm max-w-sm text-center" {
+                "Scan the code with your authenticator app, or enter the key by hand, then type the code it shows."
+            }
+            @if let Some(qr) = qr {
+                div."bg-white p-2 rounded-md" { (PreEscaped(qr)) }
+            }
+            code #totp-secret ."text-sm break-all" { (secret) }
+            div #err {}
+            form."flex flex-col items-center space-y-4" {
+                input."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".text-black name="code" type="text" autocomplete="one-time-code" placeholder="Code" {}
+                button."rounded-md border border-zinc-100/95 dark:border-zinc-800/95 p-2".w-full
+                hx-post="/account/2fa" hx-target="#two-factor" "hx-target-error"="#err"
+                {
+                    "Turn on"
+                }
+            }
+        }
+    };
+
+    Ok(b.render(html!{
+        div.flex.flex-col.items-center."p-8" hx-ext="response-targets" {
+            div #two-factor ."flex flex-col items-center space-y-4 border border-zinc-100/95 dark:border-zinc-800/95 p-4 rounded-md" {
+                (content)
+            }
+        }
+    }))
+}
+
+/// Turn on two-factor authentication once the user proved their app has the new secret.
+#[tracing::instrument(skip_all, fields(user = %session.id()))]
+pub async fn enable(State(state): State<Context>, session: Session, Form(info): Form<CodeInfo>) -> Result<Markup, Error> {
+    if !state.two_factor {
+        return Err(unavailable());
+    }
+
+    let db = state.privileged().await?;
+
+    if !check(&state, &db, session.id(), &info.code, true).await? {
+        return Err(Error::Validation("That code is not valid, check the clock of your device.".to_string()));
+    }
+
+    db.query("UPDATE $user SET totp_secret = totp_pending_secret, totp_pending_secret = NONE, totp_enabled = true")
+        .bind(("user", session.id()))
+        .await?
+        .check()?;
+
+    let codes = replace_recovery_codes(&db, session.id()).await?;
+    state.session_cache.forget_user(session.id());
+    tracing::info!("Two-factor authentication turned on");
+
+    Ok(html! {
+        h1."text-4xl".font-bold { "Two-factor authentication is on" }
+        (recovery_codes(&codes))
+    })
+}
+
+/// Hand out a new set of recovery codes, invalidating the old ones.
+#[tracing::instrument(skip_all, fields(user = %session.id()))]
+pub async fn regenerate(State(state): State<Context>, session: Session, Form(info): Form<CodeInfo>) -> Result<Markup, Error> {
+    if !state.two_factor {
+        return Err(unavailable());
+    }
+
+    let db = state.privileged().await?;
+
+    if !session.has_two_factor() || !check(&state, &db, session.id(), &info.code, false).await? {
+        return Err(Error::Validation("That code is not valid.".to_string()));
+    }
+
+    let codes = replace_recovery_codes(&db, session.id()).await?;
+
+    Ok(html! {
+        h1."text-4xl".font-bold { "New recovery codes" }
+        (recovery_codes(&codes))
+    })
+}
+
+/// Turn off two-factor authentication; needs a current code or a recovery code.
+#[tracing::instrument(skip_all, fields(user = %session.id()))]
+pub async fn disable(State(state): State<Context>, session: Session, Form(info): Form<CodeInfo>) -> Result<Response, Error> {
+    if !state.two_factor {
+        return Err(unavailable());
+    }
+
+    let db = state.privileged().await?;
+
+    if !session.has_two_factor() || !check(&state, &db, session.id(), &info.code, false).await? {
+        return Err(Error::Validation("That code is not valid.".to_string()));
+    }
+
+    turn_off(&db, session.id()).await?;
+    state.session_cache.forget_user(session.id());
+    tracing::info!("Two-factor authentication turned off");
+
+    let (mut parts, body) = StatusCode::OK.into_response().into_parts();
+    parts.headers.insert("HX-Redirect", "/account/2fa".parse().expect("Infallible"));
+
+    Ok(Response::from_parts(parts, body))
+}
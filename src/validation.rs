@@ -0,0 +1,160 @@
+use axum::response::{IntoResponse, Response};
+use http::StatusCode;
+use maud::{html, Markup};
+
+/// Fields of the sign up form, in the order they are rendered.
+pub const SIGNUP_FIELDS: &[&str] = &["username", "password", "first_name", "last_name", "email"];
+/// Fields of the password reset form.
+pub const RESET_FIELDS: &[&str] = &["password", "confirm"];
+
+const USERNAME_LEN: std::ops::RangeInclusive<usize> = 3..=32;
+const PASSWORD_LEN: std::ops::RangeInclusive<usize> = 8..=128;
+const NAME_LEN: std::ops::RangeInclusive<usize> = 1..=64;
+
+/// Problems with a submitted form, keyed by field name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldErrors {
+    /// Every field of the form, so slots of valid fields are cleared too.
+    form: &'static [&'static str],
+    fields: Vec<(&'static str, String)>,
+    /// Problem that does not belong to a single field.
+    general: Option<String>,
+}
+
+impl FieldErrors {
+    #[must_use]
+    pub fn new(form: &'static [&'static str]) -> Self {
+        Self { form, fields: Vec::new(), general: None }
+    }
+
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        if self.get(field).is_none() {
+            self.fields.push((field, message.into()));
+        }
+    }
+
+    pub fn set_general(&mut self, message: impl Into<String>) {
+        self.general = Some(message.into());
+    }
+
+    #[must_use]
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.fields.iter().find(|(name, _)| *name == field).map(|(_, message)| message.as_str())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.general.is_none()
+    }
+}
+
+/// Renders one out of band fragment per form field, so HTMX replaces every error slot,
+/// clearing the ones that are now valid, and the general message for the `#err` target.
+impl IntoResponse for FieldErrors {
+    fn into_response(self) -> Response {
+        let markup = html! {
+            @if let Some(general) = &self.general {
+                div ."bg-red-100 border border-red-400 text-red-700 px-4 py-2 rounded relative" role="alert" {
+                    (general)
+                }
+            }
+            @for field in self.form {
+                (field_error(field, self.get(field), true))
+            }
+        };
+
+        (StatusCode::UNPROCESSABLE_ENTITY, markup).into_response()
+    }
+}
+
+/// The error slot rendered under a form input, `#err-<field>`.
+#[must_use]
+pub fn field_error(field: &str, message: Option<&str>, oob: bool) -> Markup {
+    html! {
+        p id=(format!("err-{field}")) ."text-sm text-red-600 dark:text-red-400 w-full" role=[message.map(|_| "alert")] hx-swap-oob=[oob.then_some("true")] {
+            @if let Some(message) = message {
+                (message)
+            }
+        }
+    }
+}
+
+/// Letters, digits, `_` and `-`, 3 to 32 characters.
+pub fn username(errors: &mut FieldErrors, username: &str) {
+    let username = username.trim();
+
+    if !USERNAME_LEN.contains(&username.chars().count()) {
+        errors.add("username", format!("Username must be between {} and {} characters.", USERNAME_LEN.start(), USERNAME_LEN.end()));
+    } else if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        errors.add("username", "Username may only contain letters, digits, '_' and '-'.");
+    }
+}
+
+/// At least 8 characters mixing two of lowercase, uppercase, digits and symbols, and not
+/// the username.
+pub fn password(errors: &mut FieldErrors, password: &str, username: &str) {
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+
+    if !PASSWORD_LEN.contains(&password.chars().count()) {
+        errors.add("password", format!("Password must be between {} and {} characters.", PASSWORD_LEN.start(), PASSWORD_LEN.end()));
+    } else if classes.into_iter().filter(|&class| class).count() < 2 {
+        errors.add("password", "Password must mix at least two of lowercase, uppercase, digits and symbols.");
+    } else if password.trim().eq_ignore_ascii_case(username.trim()) {
+        errors.add("password", "Password must not be your username.");
+    }
+}
+
+pub fn name(errors: &mut FieldErrors, field: &'static str, label: &str, name: &str) {
+    if !NAME_LEN.contains(&name.trim().chars().count()) {
+        errors.add(field, format!("{label} must be between {} and {} characters.", NAME_LEN.start(), NAME_LEN.end()));
+    }
+}
+
+/// A pragmatic shape check; the database asserts `string::is::email` as well.
+pub fn email(errors: &mut FieldErrors, email: &str) {
+    let valid = email.len() <= 254
+        && !email.chars().any(char::is_whitespace)
+        && email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() >= 2
+                && domain.split('.').all(|label| !label.is_empty())
+        });
+
+    if !valid {
+        errors.add("email", "Enter a valid email address.");
+    }
+}
+
+/// Map a failed sign up to field messages.
+///
+/// The `account` scope throws `field: message` for conflicts it detects itself; raw
+/// constraint errors are only visible when the server forwards scope errors.
+#[must_use]
+pub fn from_signup_error(error: &surrealdb::Error) -> Option<FieldErrors> {
+    let message = error.to_string();
+    let mut errors = FieldErrors::new(SIGNUP_FIELDS);
+
+    if let Some((field, text)) = message
+        .split_once("An error occurred: ")
+        .and_then(|(_, thrown)| thrown.split_once(": "))
+    {
+        let field = SIGNUP_FIELDS.iter().find(|name| **name == field.trim())?;
+        errors.add(field, text.trim());
+    } else if message.contains("userEmailIndex") {
+        errors.add("email", "An account with this email already exists.");
+    } else if message.contains("already exists") && message.contains("user:") {
+        errors.add("username", "This username is taken.");
+    } else if message.contains("field `email`") {
+        errors.add("email", "Enter a valid email address.");
+    } else {
+        return None;
+    }
+
+    Some(errors)
+}
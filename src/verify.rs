@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use axum::extract::{Path, State};
+use maud::{html, Markup};
+use surrealdb::{Surreal, engine::any::Any, sql::Thing};
+
+use crate::{auth::Session, error::Error, state::{Context, State as AppState}, template::Template, token};
+
+/// Create a verification token for `user`, bound to `email`, valid for `ttl`.
+///
+/// # Errors
+///
+/// Returns an error if the database query fails.
+pub async fn create(db: &Surreal<Any>, user: &Thing, email: &str, ttl: Duration) -> Result<String, Error> {
+    let token = token::generate();
+
+    db.query("CREATE email_verification SET user = $user, email = $email, token_hash = $hash, expires_at = time::now() + $ttl")
+        .bind(("user", user))
+        .bind(("email", email))
+        .bind(("hash", token::hash(&token)))
+        .bind(("ttl", surrealdb::sql::Duration::from(ttl)))
+        .await?
+        .check()?;
+
+    Ok(token)
+}
+
+/// Mark the account of a verification token as verified, as long as its email did not
+/// change since the token was sent, and return it. Every token of the user is used up.
+///
+/// # Errors
+///
+/// Returns `Error::Validation` if the token is unknown or expired, or the email of the
+/// account changed.
+pub async fn consume(db: &Surreal<Any>, token: &str) -> Result<Thing, Error> {
+    let res = db.query("
+        BEGIN TRANSACTION;
+        LET $verification = (SELECT * FROM email_verification WHERE token_hash = $hash AND expires_at > time::now())[0];
+        IF !$verification THEN
+            THROW 'invalid verification token'
+        END;
+        DELETE email_verification WHERE user = $verification.user;
+        UPDATE user SET verified = true WHERE id = $verification.user AND email = $verification.email RETURN VALUE id;
+        COMMIT TRANSACTION;
+    ")
+        .bind(("hash", token::hash(token)))
+        .await?
+        .check();
+
+    // LET, IF and DELETE come before the UPDATE.
+    match res.map(|mut res| res.take::<Option<Thing>>(3)) {
+        Ok(Ok(Some(user))) => Ok(user),
+        Ok(Ok(None)) => Err(Error::Validation("The email of this account changed since the link was sent, ask for a new one.".to_string())),
+        Ok(Err(e)) => Err(e.into()),
+        Err(e) if crate::error::is_connection_error(&e) => Err(e.into()),
+        Err(e) => {
+            tracing::info!(error = %e, "Email verification rejected");
+            Err(Error::Validation("This verification link is invalid or has expired.".to_string()))
+        }
+    }
+}
+
+/// Email `user` a fresh verification link, unless one was sent within the resend cooldown.
+///
+/// # Errors
+///
+/// Returns `Error::RateLimited` during the cooldown, `Error::Unavailable` when mail is
+/// disabled, or an error if the token cannot be stored or the email cannot be sent.
+pub async fn send(state: &AppState, user: &Thing, email: &str) -> Result<(), Error> {
+    let Some(mailer) = &state.mailer else {
+        return Err(Error::Unavailable("email verification needs mail to be enabled".to_string()));
+    };
+
+    let db = state.privileged().await?;
+
+    let cooldown = state.verify_resend_cooldown;
+    let recent: Option<i64> = db.query("RETURN count(SELECT id FROM email_verification WHERE user = $user AND created_at > time::now() - $cooldown)")
+        .bind(("user", user))
+        .bind(("cooldown", surrealdb::sql::Duration::from(cooldown)))
+        .await?
+        .take(0)?;
+
+    if recent.unwrap_or_default() > 0 {
+        return Err(Error::RateLimited { retry_after_secs: cooldown.as_secs() });
+    }
+
+    let token = create(&db, user, email, state.verify_token_ttl).await?;
+    drop(db);
+
+    let link = format!("{}/auth/verify/{token}", state.base_url);
+    let hours = state.verify_token_ttl.as_secs() / 3600;
+    let body = format!(
+        "Welcome to Stickers!\n\n\
+         Confirm your email address by opening this link within {hours} hours:\n{link}\n\n\
+         If you did not create an account, you can ignore this email.\n"
+    );
+
+    mailer.send(email, "Confirm your Stickers email", body).await.map_err(|e| {
+        tracing::error!(error = %e, "Failed to send verification email");
+        Error::Unavailable("could not send the verification email".to_string())
+    })
+}
+
+pub async fn verify(State(state): State<Context>, b: Template, Path(token): Path<String>) -> Result<Markup, Error> {
+    let db = state.privileged().await?;
+    let user = consume(&db, &token).await?;
+    state.session_cache.forget_user(&user);
+
+    Ok(b.render(html!{
+        div.flex.flex-col.justify-center.items-center."h-[60vh]"."space-y-4" {
+            h1."text-4xl".font-bold { "Email verified" }
+            p."text-foreground/60" { "Thanks for confirming your email address." }
+            a."underline" href="/" { "Continue" }
+        }
+    }))
+}
+
+/// Send another verification email to the signed in user.
+pub async fn resend(State(state): State<Context>, session: Session) -> Result<Markup, Error> {
+    if session.is_verified() {
+        return Ok(html! { "Your email is already verified." });
+    }
+
+    send(&state, session.id(), session.email()).await?;
+
+    Ok(html! { "We sent you a new verification email." })
+}
+
+/// Send the verification email for a new account. Failures are only logged, the user can
+/// ask for another email later.
+pub async fn send_after_signup(state: &AppState, username: &str, email: &str) {
+    if state.mailer.is_none() {
+        return;
+    }
+
+    let user = Thing::from(("user", username.trim()));
+
+    if let Err(e) = send(state, &user, email).await {
+        tracing::warn!(error = %e, "Could not send verification email after sign up");
+    }
+}
+
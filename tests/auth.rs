@@ -0,0 +1,125 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::TestApp;
+
+#[tokio::test]
+async fn signup_signs_in_and_redirects_home() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.signup("alice", "correct horse").await;
+
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.header("HX-Redirect"), Some("/"));
+    assert!(app.is_signed_in());
+
+    let res = app.get("/").await;
+    assert!(res.body.contains("Sign out"), "navbar should show the signed in user");
+}
+
+#[tokio::test]
+async fn signup_rejects_taken_username() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.clear_cookie();
+
+    let res = app.signup("alice", "another password").await;
+
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(res.body.contains("This username is taken."));
+    assert!(!app.is_signed_in());
+}
+
+#[tokio::test]
+async fn signup_rejects_taken_email() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.clear_cookie();
+
+    let res = app.htmx_post("/auth/signup", &[
+        ("username", "bob"),
+        ("password", "correct horse"),
+        ("first_name", "Bob"),
+        ("last_name", "User"),
+        ("email", "alice@example.com"),
+    ]).await;
+
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(res.body.contains("An account with this email already exists."));
+    assert!(!app.is_signed_in());
+}
+
+#[tokio::test]
+async fn signup_reports_every_invalid_field() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.htmx_post("/auth/signup", &[
+        ("username", "a b"),
+        ("password", "short"),
+        ("first_name", ""),
+        ("last_name", "User"),
+        ("email", "not-an-email"),
+    ]).await;
+
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(res.body.contains("Username must be between"));
+    assert!(res.body.contains("Password must be between"));
+    assert!(res.body.contains("First name must be between"));
+    assert!(res.body.contains("Enter a valid email address."));
+    assert!(res.body.contains(r#"id="err-last_name""#), "valid fields still get their slot cleared");
+    assert!(!app.is_signed_in());
+}
+
+#[tokio::test]
+async fn signin_with_valid_credentials() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.signout().await;
+    assert!(!app.is_signed_in());
+
+    let res = app.signin("alice", "correct horse").await;
+
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.header("HX-Redirect"), Some("/"));
+    assert!(app.is_signed_in());
+}
+
+#[tokio::test]
+async fn signin_with_wrong_password() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.clear_cookie();
+
+    let res = app.signin("alice", "battery staple").await;
+
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    assert!(res.body.contains("Invalid credentials."));
+    assert!(!app.is_signed_in());
+}
+
+#[tokio::test]
+async fn signed_in_users_are_redirected_away_from_signin() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+
+    let res = app.htmx_get("/auth/signin").await;
+
+    assert_eq!(res.header("HX-Redirect"), Some("/"));
+}
+
+#[tokio::test]
+async fn admin_requires_admin_user() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.get("/admin").await;
+    assert!(res.status.is_redirection(), "guests are redirected, got {}", res.status);
+
+    app.signup("alice", "correct horse").await;
+    let res = app.get("/admin").await;
+    assert!(res.status.is_redirection(), "regular users are redirected, got {}", res.status);
+
+    app.promote("alice").await;
+    let res = app.get("/admin").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.body.contains("Hola, Test!"));
+}
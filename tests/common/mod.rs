@@ -0,0 +1,305 @@
+//! Test support: the full application router over an in-memory SurrealDB with the
+//! migrations applied, a mock image server, a mock OIDC provider, an in-memory mailer, and a
+//! client that carries the session cookie.
+
+#![allow(dead_code)]
+
+pub mod oidc;
+
+use std::{collections::{BTreeMap, HashMap}, net::SocketAddr, path::Path, sync::{Arc, Mutex}, time::Duration};
+
+use axum::{Router, body::{Body, Bytes}, extract::{Path as UrlPath, State}, http::{HeaderMap, Request, StatusCode, header}, response::Response, routing::{get, post}};
+use http_body_util::BodyExt;
+use oidc::MockOidc;
+use stickers::{config::{self, AppConfig, OidcProvider, ServeMode}, mail::{Email, MemoryMailer}, migrate, pool::{self, SurrealManager}, shutdown::Shutdown, state::Context};
+use surrealdb::sql::Thing;
+use tower::ServiceExt;
+
+/// How long `TestApp::link_sent_to` waits for an email sent in the background.
+const EMAIL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stickers stored by the mock image server, by id.
+pub type Stickers = Arc<Mutex<HashMap<String, Bytes>>>;
+
+pub struct TestApp {
+    pub app: Router,
+    pub state: Context,
+    pub pool: SurrealManager,
+    pub config: AppConfig,
+    pub stickers: Stickers,
+    pub mailer: MemoryMailer,
+    pub oidc: MockOidc,
+    /// Cookies set by the app, by name.
+    cookies: BTreeMap<String, String>,
+}
+
+/// A response with its body already read.
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+impl TestApp {
+    pub async fn spawn() -> Self {
+        Self::spawn_with(|_| {}).await
+    }
+
+    /// Start the app with a config tweaked by `configure`.
+    pub async fn spawn_with(configure: impl FnOnce(&mut AppConfig)) -> Self {
+        let (img_addr, stickers) = spawn_image_server().await;
+        let provider = MockOidc::spawn().await;
+
+        let mut config = AppConfig::default();
+        config.server.mode = ServeMode::Plain;
+        config.surreal.url = "mem://".to_string();
+        config.cookie.key = Some(config::generate_key());
+        config.img_server.url = format!("http://{img_addr}");
+        config.auth.token_secret = Some(config::generate_key());
+        config.oidc.providers = vec![OidcProvider {
+            name: oidc::NAME.to_string(),
+            label: Some("Mock".to_string()),
+            issuer: provider.issuer.clone(),
+            client_id: oidc::CLIENT_ID.to_string(),
+            client_secret: Some(oidc::CLIENT_SECRET.to_string()),
+            scopes: vec!["openid".to_string(), "email".to_string(), "profile".to_string()],
+        }];
+        configure(&mut config);
+
+        let pool = pool::Manager::new(&config.surreal);
+        let root = pool::Privileged::new(&config.surreal, &pool);
+        migrate::apply(&root, Path::new("migrations"), false).await.expect("Migrations apply");
+        stickers::auth::install(&root, &config).await.expect("Session token key defined");
+
+        let mailer = MemoryMailer::default();
+        let state = Context::with_mailer(pool.clone(), root, &config, Shutdown::from_os_signals(Duration::ZERO), Some(Arc::new(mailer.clone())));
+
+        Self {
+            app: stickers::build_app(state.clone()),
+            state,
+            pool,
+            config,
+            stickers,
+            mailer,
+            oidc: provider,
+            cookies: BTreeMap::new(),
+        }
+    }
+
+    /// Send `req` with the current cookies and remember any cookie the app sets or removes.
+    pub async fn send(&mut self, mut req: Request<Body>) -> TestResponse {
+        if !self.cookies.is_empty() {
+            let cookies = self.cookies.iter().map(|(name, value)| format!("{name}={value}")).collect::<Vec<_>>().join("; ");
+            req.headers_mut().insert(header::COOKIE, cookies.parse().expect("Valid cookie header"));
+        }
+
+        let response: Response = self.app.clone().oneshot(req).await.expect("Infallible");
+
+        for set_cookie in response.headers().get_all(header::SET_COOKIE).iter().filter_map(|v| v.to_str().ok()) {
+            let pair = set_cookie.split(';').next().unwrap_or_default();
+            self.store_cookie(pair);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.expect("Readable body").to_bytes();
+
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }
+    }
+
+    pub async fn get(&mut self, path: &str) -> TestResponse {
+        self.send(Request::get(path).body(Body::empty()).expect("Valid request")).await
+    }
+
+    /// Issue a request the way HTMX does, with `HX-Request` set.
+    pub async fn htmx_get(&mut self, path: &str) -> TestResponse {
+        self.send(Request::get(path).header("HX-Request", "true").body(Body::empty()).expect("Valid request")).await
+    }
+
+    /// Post a form the way HTMX does, with `HX-Request` set.
+    pub async fn htmx_post(&mut self, path: &str, form: &[(&str, &str)]) -> TestResponse {
+        let body = form
+            .iter()
+            .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        self.send(
+            Request::post(path)
+                .header("HX-Request", "true")
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(body))
+                .expect("Valid request"),
+        ).await
+    }
+
+    pub async fn signup(&mut self, username: &str, password: &str) -> TestResponse {
+        self.htmx_post("/auth/signup", &[
+            ("username", username),
+            ("password", password),
+            ("first_name", "Test"),
+            ("last_name", "User"),
+            ("email", &format!("{username}@example.com")),
+        ]).await
+    }
+
+    pub async fn signin(&mut self, username: &str, password: &str) -> TestResponse {
+        self.htmx_post("/auth/signin", &[("username", username), ("password", password)]).await
+    }
+
+    /// Sign in through the mock provider, following the redirects a browser would.
+    pub async fn oidc_signin(&mut self) -> TestResponse {
+        let res = self.get(&format!("/auth/oidc/{}/start", oidc::NAME)).await;
+        let location = res.header("location").expect("Redirect to the provider").to_string();
+
+        let callback = self.oidc.authorize(&location);
+        self.get(&callback).await
+    }
+
+    pub async fn signout(&mut self) -> TestResponse {
+        self.htmx_get("/signout").await
+    }
+
+    pub fn is_signed_in(&self) -> bool {
+        self.cookies.contains_key(&self.config.cookie.name)
+    }
+
+    /// Forget every cookie without signing out.
+    pub fn clear_cookie(&mut self) {
+        self.cookies.clear();
+    }
+
+    /// The session cookie currently carried, as a `name=value` pair.
+    pub fn cookie(&self) -> Option<String> {
+        let name = &self.config.cookie.name;
+
+        self.cookies.get(name).map(|value| format!("{name}={value}"))
+    }
+
+    /// Carry `cookie` as the session cookie from now on, e.g. one saved with
+    /// [`TestApp::cookie`].
+    pub fn set_cookie(&mut self, cookie: Option<String>) {
+        self.cookies.remove(&self.config.cookie.name);
+
+        if let Some(cookie) = cookie {
+            self.store_cookie(&cookie);
+        }
+    }
+
+    /// Remember a `name=value` pair; an empty value removes the cookie.
+    fn store_cookie(&mut self, pair: &str) {
+        match pair.split_once('=') {
+            Some((name, "")) => {
+                self.cookies.remove(name);
+            }
+            Some((name, value)) => {
+                self.cookies.insert(name.to_string(), value.to_string());
+            }
+            None => {}
+        }
+    }
+
+    /// Emails sent so far. Most are sent from background tasks, wait for those with
+    /// [`TestApp::link_sent_to`].
+    pub fn emails(&self) -> Vec<Email> {
+        self.mailer.sent()
+    }
+
+    /// The path of the last link to `path` on this site in an email to `to`, waiting for the
+    /// email to be sent for up to `EMAIL_TIMEOUT`.
+    pub async fn link_sent_to(&self, to: &str, path: &str) -> Option<String> {
+        let base = &self.config.mail.base_url;
+        let deadline = tokio::time::Instant::now() + EMAIL_TIMEOUT;
+
+        loop {
+            let link = self.emails()
+                .iter()
+                .rev()
+                .filter(|email| email.to == to)
+                .find_map(|email| email.body.split_whitespace().find_map(|word| word.strip_prefix(base.as_str())).filter(|link| link.starts_with(path)).map(str::to_string));
+
+            if link.is_some() || tokio::time::Instant::now() >= deadline {
+                return link;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Create a password reset token for `email` the way `/auth/forgot` does, without mail.
+    pub async fn reset_token(&self, email: &str) -> Option<String> {
+        let db = self.state.root.get().await.expect("Root connection");
+
+        stickers::reset::create(&db, email, Duration::from_secs(60)).await.expect("Create reset token")
+    }
+
+    /// Make `username` an administrator, as `stickers user promote` does. The CLI cannot
+    /// reach the session cache of a running server, so its change shows up once the cached
+    /// sessions expire; here they are forgotten right away.
+    pub async fn promote(&self, username: &str) {
+        self.state.root.get().await.expect("Root connection")
+            .query("UPDATE type::thing('user', $username) SET is_admin = true")
+            .bind(("username", username))
+            .await
+            .expect("Promote user")
+            .check()
+            .expect("Promote user");
+
+        self.state.session_cache.forget_user(&Thing::from(("user", username)));
+    }
+}
+
+/// A sticker upload of `body`.
+pub fn upload(body: &'static [u8]) -> Request<Body> {
+    Request::post("/upload")
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .expect("Valid request")
+}
+
+fn encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{b:02X}"),
+    }).collect()
+}
+
+/// Start an image server stand-in speaking HTTP/2 without TLS, like the real one.
+async fn spawn_image_server() -> (SocketAddr, Stickers) {
+    async fn upload(State(stickers): State<Stickers>, body: Bytes) -> String {
+        let mut stickers = stickers.lock().expect("Lock");
+        let id = (stickers.len() + 1).to_string();
+        stickers.insert(id.clone(), body);
+        id
+    }
+
+    async fn fetch(State(stickers): State<Stickers>, UrlPath(id): UrlPath<String>) -> Result<Bytes, StatusCode> {
+        stickers.lock().expect("Lock").get(&id).cloned().ok_or(StatusCode::NOT_FOUND)
+    }
+
+    let stickers = Stickers::default();
+
+    let app = Router::new()
+        .route("/new", post(upload))
+        .route("/:id", get(fetch))
+        .with_state(stickers.clone());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Bind mock image server");
+    let addr = listener.local_addr().expect("Local address");
+
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service()).await.expect("Mock image server");
+    });
+
+    (addr, stickers)
+}
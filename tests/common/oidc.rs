@@ -0,0 +1,193 @@
+//! A stand-in OpenID Connect provider serving discovery, keys and a token endpoint that
+//! checks PKCE. There is no login page: [`MockOidc::authorize`] approves an authorization
+//! request in process for the user set with [`MockOidc::set_user`].
+
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::{SystemTime, UNIX_EPOCH}};
+
+use axum::{Form, Json, Router, extract::State, http::StatusCode, routing::{get, post}};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+pub const NAME: &str = "mock";
+pub const CLIENT_ID: &str = "stickers";
+pub const CLIENT_SECRET: &str = "client secret";
+const KEY_ID: &str = "test-key";
+
+/// The account the provider signs in as.
+#[derive(Debug, Clone)]
+pub struct MockUser {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub given_name: String,
+    pub family_name: String,
+}
+
+impl Default for MockUser {
+    fn default() -> Self {
+        Self {
+            sub: "mock-1".to_string(),
+            email: Some("olivia@example.com".to_string()),
+            email_verified: true,
+            given_name: "Olivia".to_string(),
+            family_name: "Provider".to_string(),
+        }
+    }
+}
+
+struct Authorization {
+    nonce: String,
+    challenge: String,
+    redirect_uri: String,
+    user: MockUser,
+}
+
+#[derive(Clone)]
+pub struct MockOidc {
+    pub issuer: String,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    key: EncodingKey,
+    jwk: Value,
+    user: Mutex<MockUser>,
+    codes: Mutex<HashMap<String, Authorization>>,
+    /// Requests for the signing keys so far.
+    key_fetches: AtomicUsize,
+}
+
+impl MockOidc {
+    pub async fn spawn() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Bind mock OIDC provider");
+        let addr = listener.local_addr().expect("Local address");
+
+        let key = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).expect("Generate signing key");
+        // Uncompressed point: 0x04, then x and y.
+        let point = key.public_key_raw();
+
+        let mock = Self {
+            issuer: format!("http://{addr}"),
+            inner: Arc::new(Inner {
+                key: EncodingKey::from_ec_der(&key.serialize_der()),
+                jwk: json!({
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                    "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+                    "kid": KEY_ID,
+                    "alg": "ES256",
+                    "use": "sig",
+                }),
+                user: Mutex::new(MockUser::default()),
+                codes: Mutex::new(HashMap::new()),
+                key_fetches: AtomicUsize::new(0),
+            }),
+        };
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(mock.clone());
+
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service()).await.expect("Mock OIDC provider");
+        });
+
+        mock
+    }
+
+    /// How many times the signing keys were requested.
+    pub fn key_fetches(&self) -> usize {
+        self.inner.key_fetches.load(Ordering::SeqCst)
+    }
+
+    pub fn set_user(&self, user: MockUser) {
+        *self.inner.user.lock().expect("Lock") = user;
+    }
+
+    /// Approve the authorization request the app redirected to, returning the path of the
+    /// callback the provider would send the browser to.
+    pub fn authorize(&self, location: &str) -> String {
+        let url = reqwest::Url::parse(location).expect("Authorization URL");
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        let mut codes = self.inner.codes.lock().expect("Lock");
+        let code = format!("code-{}", codes.len());
+        codes.insert(code.clone(), Authorization {
+            nonce: params["nonce"].clone(),
+            challenge: params["code_challenge"].clone(),
+            redirect_uri: params["redirect_uri"].clone(),
+            user: self.inner.user.lock().expect("Lock").clone(),
+        });
+
+        let mut callback = reqwest::Url::parse(&params["redirect_uri"]).expect("Redirect URI");
+        callback.query_pairs_mut()
+            .append_pair("code", &code)
+            .append_pair("state", &params["state"]);
+
+        format!("{}?{}", callback.path(), callback.query().unwrap_or_default())
+    }
+}
+
+async fn discovery(State(mock): State<MockOidc>) -> Json<Value> {
+    Json(json!({
+        "issuer": mock.issuer,
+        "authorization_endpoint": format!("{}/authorize", mock.issuer),
+        "token_endpoint": format!("{}/token", mock.issuer),
+        "jwks_uri": format!("{}/jwks", mock.issuer),
+    }))
+}
+
+async fn jwks(State(mock): State<MockOidc>) -> Json<Value> {
+    mock.inner.key_fetches.fetch_add(1, Ordering::SeqCst);
+    Json(json!({ "keys": [mock.inner.jwk] }))
+}
+
+async fn token(State(mock): State<MockOidc>, Form(form): Form<HashMap<String, String>>) -> Result<Json<Value>, StatusCode> {
+    let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+
+    let authorization = mock.inner.codes.lock().expect("Lock").remove(field("code")).ok_or(StatusCode::BAD_REQUEST)?;
+    let verifier_matches = URL_SAFE_NO_PAD.encode(Sha256::digest(field("code_verifier").as_bytes())) == authorization.challenge;
+
+    if field("grant_type") != "authorization_code"
+        || field("client_id") != CLIENT_ID
+        || field("client_secret") != CLIENT_SECRET
+        || field("redirect_uri") != authorization.redirect_uri
+        || !verifier_matches
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("After the epoch").as_secs();
+    let user = authorization.user;
+    let claims = json!({
+        "iss": mock.issuer,
+        "aud": CLIENT_ID,
+        "sub": user.sub,
+        "iat": now,
+        "exp": now + 300,
+        "nonce": authorization.nonce,
+        "email": user.email,
+        "email_verified": user.email_verified,
+        "given_name": user.given_name,
+        "family_name": user.family_name,
+    });
+
+    let mut header = Header::new(Algorithm::ES256);
+    header.kid = Some(KEY_ID.to_string());
+    let id_token = jsonwebtoken::encode(&header, &claims, &mock.inner.key).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "access_token": "unused",
+        "token_type": "Bearer",
+        "id_token": id_token,
+    })))
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, oidc::MockUser};
+use surrealdb::sql::Thing;
+
+async fn users(app: &TestApp) -> Vec<Thing> {
+    app.state.root.get().await.expect("Root connection")
+        .query("SELECT VALUE id FROM user")
+        .await
+        .expect("List users")
+        .take(0)
+        .expect("User ids")
+}
+
+#[tokio::test]
+async fn first_sign_in_creates_an_account() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.oidc_signin().await;
+
+    assert!(res.status.is_redirection(), "got {}", res.status);
+    assert_eq!(res.header("location"), Some("/"));
+    assert!(app.is_signed_in());
+
+    let res = app.get("/").await;
+    assert!(res.body.contains("Olivia Provider"), "the minted token should authenticate the session");
+    assert_eq!(users(&app).await, vec![Thing::from(("user", "olivia"))]);
+}
+
+#[tokio::test]
+async fn signing_keys_are_fetched_once() {
+    let mut app = TestApp::spawn().await;
+    app.oidc_signin().await;
+    app.signout().await;
+    app.oidc_signin().await;
+
+    assert!(app.is_signed_in());
+    assert_eq!(app.oidc.key_fetches(), 1);
+}
+
+#[tokio::test]
+async fn returning_identity_signs_into_the_same_account() {
+    let mut app = TestApp::spawn().await;
+    app.oidc_signin().await;
+    app.signout().await;
+
+    let res = app.oidc_signin().await;
+
+    assert_eq!(res.header("location"), Some("/"));
+    assert!(app.is_signed_in());
+    assert_eq!(users(&app).await.len(), 1);
+}
+
+#[tokio::test]
+async fn forged_state_is_rejected() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.get("/auth/oidc/mock/start").await;
+    let location = res.header("location").expect("Redirect to the provider").to_string();
+    let callback = app.oidc.authorize(&location).replace("state=", "state=forged");
+
+    let res = app.get(&callback).await;
+
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(!app.is_signed_in());
+}
+
+#[tokio::test]
+async fn existing_email_is_not_linked() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.clear_cookie();
+
+    app.oidc.set_user(MockUser {
+        sub: "someone-else".to_string(),
+        email: Some("alice@example.com".to_string()),
+        ..MockUser::default()
+    });
+    let res = app.oidc_signin().await;
+
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert!(!app.is_signed_in());
+    assert_eq!(users(&app).await.len(), 1);
+}
+
+#[tokio::test]
+async fn unknown_provider_is_not_found() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.get("/auth/oidc/nope/start").await;
+
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, upload};
+
+#[tokio::test]
+async fn upload_then_get_round_trips_through_image_server() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.send(upload(b"sticker bytes")).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let id = res.body;
+
+    assert_eq!(app.stickers.lock().expect("Lock").len(), 1);
+
+    let res = app.get(&format!("/get/{id}")).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, "sticker bytes");
+}
+
+#[tokio::test]
+async fn get_unknown_sticker_passes_through_not_found() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.get("/get/missing").await;
+
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn upload_over_limit_is_rejected_before_proxying() {
+    let mut app = TestApp::spawn_with(|config| config.img_server.max_upload_bytes = 4).await;
+
+    let res = app.send(upload(b"too large")).await;
+
+    assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(app.stickers.lock().expect("Lock").is_empty());
+}
+
+#[tokio::test]
+async fn requests_get_a_request_id() {
+    let mut app = TestApp::spawn().await;
+
+    let res = app.get("/healthz").await;
+
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.header(stickers::logging::REQUEST_ID_HEADER).is_some());
+}
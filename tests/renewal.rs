@@ -0,0 +1,70 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::TestApp;
+
+/// Renew on every request: scope tokens always expire within a day.
+const ALWAYS: u64 = 24 * 60 * 60;
+
+#[tokio::test]
+async fn expiring_token_is_renewed() {
+    let mut app = TestApp::spawn_with(|config| config.auth.renew_within_secs = ALWAYS).await;
+    app.signup("alice", "correct horse").await;
+    let before = app.cookie();
+
+    app.get("/").await;
+    assert_ne!(app.cookie(), before, "a fresh cookie should be set");
+
+    let res = app.get("/account/sessions").await;
+    assert_eq!(res.status, StatusCode::OK, "the renewed token must be accepted");
+    assert!(res.body.contains("This device"), "the renewed token keeps its session");
+}
+
+#[tokio::test]
+async fn fresh_token_is_kept() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    let before = app.cookie();
+
+    app.get("/").await;
+
+    assert_eq!(app.cookie(), before);
+}
+
+#[tokio::test]
+async fn session_past_max_age_is_not_renewed() {
+    let mut app = TestApp::spawn_with(|config| {
+        config.auth.renew_within_secs = ALWAYS;
+        config.auth.max_session_age_secs = 1;
+    }).await;
+    app.signup("alice", "correct horse").await;
+    let before = app.cookie();
+
+    app.get("/").await;
+
+    assert_eq!(app.cookie(), before);
+}
+
+#[tokio::test]
+async fn nothing_is_renewed_without_a_token_secret() {
+    let mut app = TestApp::spawn_with(|config| {
+        config.auth.renew_within_secs = ALWAYS;
+        config.auth.token_secret = None;
+    }).await;
+    app.signup("alice", "correct horse").await;
+    let before = app.cookie();
+
+    app.get("/").await;
+
+    assert_eq!(app.cookie(), before);
+}
+
+#[tokio::test]
+async fn renewal_does_not_undo_signing_out() {
+    let mut app = TestApp::spawn_with(|config| config.auth.renew_within_secs = ALWAYS).await;
+    app.signup("alice", "correct horse").await;
+
+    app.signout().await;
+
+    assert!(!app.is_signed_in());
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::TestApp;
+
+#[tokio::test]
+async fn reset_sets_new_password_and_revokes_sessions() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    let old_session = app.cookie();
+    app.clear_cookie();
+
+    let token = app.reset_token("alice@example.com").await.expect("Known email");
+    let res = app.htmx_post(&format!("/auth/reset/{token}"), &[("password", "new password 1"), ("confirm", "new password 1")]).await;
+
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.header("HX-Redirect"), Some("/auth/signin"));
+
+    app.set_cookie(old_session);
+    let res = app.get("/").await;
+    assert!(!res.body.contains("Sign out"), "session issued before the reset must be rejected");
+
+    app.clear_cookie();
+    assert_eq!(app.signin("alice", "correct horse").await.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(app.signin("alice", "new password 1").await.status, StatusCode::OK);
+
+    let res = app.get("/account/sessions").await;
+    assert_eq!(res.status, StatusCode::OK, "signing in right after the reset must give a working session");
+    assert!(res.body.contains("Sign out"));
+}
+
+#[tokio::test]
+async fn reset_token_is_single_use() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.clear_cookie();
+
+    let token = app.reset_token("alice@example.com").await.expect("Known email");
+    let path = format!("/auth/reset/{token}");
+
+    assert_eq!(app.htmx_post(&path, &[("password", "new password 1"), ("confirm", "new password 1")]).await.status, StatusCode::OK);
+
+    let res = app.htmx_post(&path, &[("password", "new password 2"), ("confirm", "new password 2")]).await;
+    assert!(res.status.is_client_error());
+    assert!(res.body.contains("invalid or has expired"));
+}
+
+#[tokio::test]
+async fn reset_for_unknown_email_creates_no_token() {
+    let app = TestApp::spawn().await;
+
+    assert!(app.reset_token("nobody@example.com").await.is_none());
+}
+
+#[tokio::test]
+async fn reset_requires_matching_passwords() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.clear_cookie();
+
+    let token = app.reset_token("alice@example.com").await.expect("Known email");
+    let res = app.htmx_post(&format!("/auth/reset/{token}"), &[("password", "new password 1"), ("confirm", "new password 2")]).await;
+
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(res.body.contains("Passwords do not match."));
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use common::TestApp;
+
+/// Close every session of alice behind the app's back, like another instance would.
+async fn close_sessions_elsewhere(app: &TestApp) {
+    app.state.root.get().await.expect("Root connection")
+        .query("DELETE session WHERE user = user:alice")
+        .await
+        .expect("Close sessions")
+        .check()
+        .expect("Close sessions");
+}
+
+/// Long enough for a few requests, short enough to wait out.
+const SHORT_TTL: Duration = Duration::from_secs(1);
+
+async fn spawn_with_short_ttl() -> TestApp {
+    TestApp::spawn_with(|config| config.auth.session_cache_ttl_secs = SHORT_TTL.as_secs()).await
+}
+
+#[tokio::test]
+async fn sessions_closed_elsewhere_apply_once_the_cache_expires() {
+    let mut app = spawn_with_short_ttl().await;
+    app.signup("alice", "correct horse").await;
+    assert_eq!(app.get("/account/sessions").await.status, StatusCode::OK);
+
+    close_sessions_elsewhere(&app).await;
+    tokio::time::sleep(SHORT_TTL).await;
+
+    assert_eq!(app.get("/account/sessions").await.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn demoting_elsewhere_applies_once_the_cache_expires() {
+    let mut app = spawn_with_short_ttl().await;
+    app.signup("alice", "correct horse").await;
+    app.promote("alice").await;
+    assert_eq!(app.get("/admin").await.status, StatusCode::OK);
+
+    app.state.root.get().await.expect("Root connection")
+        .query("UPDATE user:alice SET is_admin = false")
+        .await
+        .expect("Demote user")
+        .check()
+        .expect("Demote user");
+    tokio::time::sleep(SHORT_TTL).await;
+
+    let res = app.get("/admin").await;
+    assert!(res.status.is_redirection(), "a demoted admin is redirected, got {}", res.status);
+}
+
+#[tokio::test]
+async fn disabled_cache_checks_every_request() {
+    let mut app = TestApp::spawn_with(|config| config.auth.session_cache_ttl_secs = 0).await;
+    app.signup("alice", "correct horse").await;
+    assert_eq!(app.get("/account/sessions").await.status, StatusCode::OK);
+
+    close_sessions_elsewhere(&app).await;
+
+    let res = app.get("/").await;
+    assert!(!res.body.contains("Sign out"));
+}
+
+#[tokio::test]
+async fn signing_out_is_not_hidden_by_the_cache() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.get("/").await;
+    let cookie = app.cookie();
+
+    app.signout().await;
+    app.set_cookie(cookie);
+
+    assert_eq!(app.get("/account/sessions").await.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn revoking_is_not_hidden_by_the_cache() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.get("/").await;
+    let first = app.cookie();
+
+    app.clear_cookie();
+    app.signin("alice", "correct horse").await;
+    let res = app.htmx_post("/account/sessions/revoke-all", &[]).await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    app.set_cookie(first);
+    assert_eq!(app.get("/account/sessions").await.status, StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::TestApp;
+
+/// Revoke links on the sessions page, one per session other than the current one.
+fn revoke_paths(body: &str) -> Vec<String> {
+    body.split("hx-post=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split_once('"').map(|(path, _)| path.to_string()))
+        .filter(|path| path.ends_with("/revoke"))
+        .collect()
+}
+
+/// Sign alice up, then in again from a second device. Returns the cookie of the first one.
+async fn two_sessions(app: &mut TestApp) -> Option<String> {
+    app.signup("alice", "correct horse").await;
+    let first = app.cookie();
+
+    app.clear_cookie();
+    app.signin("alice", "correct horse").await;
+
+    first
+}
+
+#[tokio::test]
+async fn sessions_page_lists_every_device() {
+    let mut app = TestApp::spawn().await;
+    two_sessions(&mut app).await;
+
+    let res = app.get("/account/sessions").await;
+
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.body.contains("This device"));
+    assert_eq!(revoke_paths(&res.body).len(), 1);
+}
+
+#[tokio::test]
+async fn signing_out_revokes_the_token() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    let cookie = app.cookie();
+
+    app.signout().await;
+    app.set_cookie(cookie);
+
+    let res = app.get("/account/sessions").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED, "the cookie must not outlive signing out");
+}
+
+#[tokio::test]
+async fn revoked_session_is_rejected() {
+    let mut app = TestApp::spawn().await;
+    let first = two_sessions(&mut app).await;
+
+    let res = app.get("/account/sessions").await;
+    let path = revoke_paths(&res.body).pop().expect("Other session listed");
+
+    let res = app.htmx_post(&path, &[]).await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    let res = app.get("/account/sessions").await;
+    assert_eq!(res.status, StatusCode::OK, "the current session stays open");
+    assert!(revoke_paths(&res.body).is_empty());
+
+    app.set_cookie(first);
+    let res = app.get("/account/sessions").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn sign_out_everywhere_ends_every_session() {
+    let mut app = TestApp::spawn().await;
+    let first = two_sessions(&mut app).await;
+    let second = app.cookie();
+
+    let res = app.htmx_post("/account/sessions/revoke-all", &[]).await;
+    assert_eq!(res.header("HX-Redirect"), Some("/"));
+    assert!(!app.is_signed_in());
+
+    for cookie in [first, second] {
+        app.set_cookie(cookie);
+        let res = app.get("/account/sessions").await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn sessions_of_other_users_cannot_be_revoked() {
+    let mut app = TestApp::spawn().await;
+    two_sessions(&mut app).await;
+    let res = app.get("/account/sessions").await;
+    let path = revoke_paths(&res.body).pop().expect("Other session listed");
+
+    app.clear_cookie();
+    app.signup("bob", "correct horse").await;
+
+    let res = app.htmx_post(&path, &[]).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
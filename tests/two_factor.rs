@@ -0,0 +1,161 @@
+mod common;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::StatusCode;
+use common::TestApp;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// The code of `secret` for the time step `steps` after the current one.
+fn code(secret: &str, steps: u64) -> String {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().expect("Base32 secret");
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("After the epoch").as_secs();
+
+    TOTP::new_unchecked(Algorithm::SHA1, 6, 0, 30, secret, None, String::new()).generate(now + 30 * steps)
+}
+
+fn wrong(code: &str) -> String {
+    format!("{:06}", (code.parse::<u32>().expect("Numeric code") + 1) % 1_000_000)
+}
+
+/// The secret being set up, as shown on the settings page.
+async fn pending_secret(app: &mut TestApp) -> String {
+    let res = app.get("/account/2fa").await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    res.body
+        .split_once("id=\"totp-secret\"")
+        .and_then(|(_, rest)| rest.split_once('>'))
+        .and_then(|(_, rest)| rest.split_once('<'))
+        .map(|(secret, _)| secret.to_string())
+        .expect("Secret on the page")
+}
+
+/// Turn on two-factor authentication for the signed in user, returning the secret and the
+/// recovery codes.
+async fn enroll(app: &mut TestApp) -> (String, Vec<String>) {
+    let secret = pending_secret(app).await;
+
+    let res = app.htmx_post("/account/2fa", &[("code", &code(&secret, 0))]).await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    let recovery = res.body
+        .split("<li>")
+        .skip(1)
+        .filter_map(|item| item.split_once("</li>").map(|(code, _)| code.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(recovery.len(), 10);
+
+    (secret, recovery)
+}
+
+async fn sign_in_again(app: &mut TestApp) {
+    app.signout().await;
+
+    let res = app.signin("alice", "correct horse").await;
+    assert_eq!(res.header("HX-Redirect"), Some("/auth/2fa"));
+    assert!(!app.is_signed_in(), "the password alone must not sign in");
+}
+
+#[tokio::test]
+async fn reloading_settings_keeps_the_secret_being_set_up() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+
+    let secret = pending_secret(&mut app).await;
+    assert_eq!(pending_secret(&mut app).await, secret);
+
+    let res = app.htmx_post("/account/2fa", &[("code", &code(&secret, 0))]).await;
+    assert_eq!(res.status, StatusCode::OK, "the app scanned first keeps working");
+}
+
+#[tokio::test]
+async fn sign_in_asks_for_code_once_enabled() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    let (secret, _) = enroll(&mut app).await;
+
+    sign_in_again(&mut app).await;
+
+    let next = code(&secret, 1);
+    let res = app.htmx_post("/auth/2fa", &[("code", &wrong(&next))]).await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(!app.is_signed_in());
+
+    let res = app.htmx_post("/auth/2fa", &[("code", &next)]).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.header("HX-Redirect"), Some("/"));
+    assert!(app.is_signed_in());
+
+    sign_in_again(&mut app).await;
+
+    let res = app.htmx_post("/auth/2fa", &[("code", &next)]).await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY, "codes are single use");
+}
+
+#[tokio::test]
+async fn recovery_codes_work_once() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    let (_, recovery) = enroll(&mut app).await;
+
+    sign_in_again(&mut app).await;
+
+    let res = app.htmx_post("/auth/2fa", &[("code", &recovery[0].to_uppercase())]).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(app.is_signed_in());
+
+    sign_in_again(&mut app).await;
+
+    let res = app.htmx_post("/auth/2fa", &[("code", &recovery[0])]).await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn wrong_codes_lock_out() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    let (secret, _) = enroll(&mut app).await;
+
+    sign_in_again(&mut app).await;
+
+    let next = code(&secret, 1);
+    for _ in 0..5 {
+        app.htmx_post("/auth/2fa", &[("code", &wrong(&next))]).await;
+    }
+
+    let res = app.htmx_post("/auth/2fa", &[("code", &next)]).await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(!app.is_signed_in());
+}
+
+#[tokio::test]
+async fn turning_off_skips_the_second_step() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    let (secret, _) = enroll(&mut app).await;
+
+    let res = app.htmx_post("/account/2fa/disable", &[("code", &code(&secret, 1))]).await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    app.signout().await;
+    let res = app.signin("alice", "correct horse").await;
+    assert_eq!(res.header("HX-Redirect"), Some("/"));
+    assert!(app.is_signed_in());
+}
+
+#[tokio::test]
+async fn admins_must_enroll_when_required() {
+    let mut app = TestApp::spawn_with(|config| config.auth.require_admin_two_factor = true).await;
+    app.signup("alice", "correct horse").await;
+    app.promote("alice").await;
+
+    let res = app.get("/admin").await;
+    assert!(res.status.is_redirection());
+    assert_eq!(res.header("location"), Some("/account/2fa"));
+
+    enroll(&mut app).await;
+
+    let res = app.get("/admin").await;
+    assert_eq!(res.status, StatusCode::OK);
+}
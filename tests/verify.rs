@@ -0,0 +1,74 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, upload};
+
+#[tokio::test]
+async fn signup_sends_verification_link() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+
+    let link = app.link_sent_to("alice@example.com", "/auth/verify/").await.expect("Verification email");
+    assert!(link.starts_with("/auth/verify/"));
+
+    let res = app.get(&link).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.body.contains("Email verified"));
+
+    let res = app.get(&link).await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY, "links are single use");
+}
+
+#[tokio::test]
+async fn unverified_accounts_cannot_upload_when_restricted() {
+    let mut app = TestApp::spawn_with(|config| config.auth.restrict_unverified = true).await;
+
+    assert_eq!(app.send(upload(b"sticker bytes")).await.status, StatusCode::UNAUTHORIZED, "guests cannot upload");
+
+    app.signup("alice", "correct horse").await;
+    let res = app.get("/").await;
+    assert!(res.body.contains("Verify your email address"));
+    assert_eq!(app.send(upload(b"sticker bytes")).await.status, StatusCode::FORBIDDEN);
+
+    let link = app.link_sent_to("alice@example.com", "/auth/verify/").await.expect("Verification email");
+    app.get(&link).await;
+
+    assert_eq!(app.send(upload(b"sticker bytes")).await.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unverified_accounts_can_upload_by_default() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+
+    assert_eq!(app.send(upload(b"sticker bytes")).await.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn resend_is_throttled() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.link_sent_to("alice@example.com", "/auth/verify/").await.expect("Verification email");
+
+    let res = app.htmx_post("/auth/verify/resend", &[]).await;
+
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.header("retry-after").is_some());
+    assert_eq!(app.emails().len(), 1);
+}
+
+#[tokio::test]
+async fn forgot_password_emails_a_reset_link() {
+    let mut app = TestApp::spawn().await;
+    app.signup("alice", "correct horse").await;
+    app.clear_cookie();
+
+    let res = app.htmx_post("/auth/forgot", &[("email", "alice@example.com")]).await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    let link = app.link_sent_to("alice@example.com", "/auth/reset/").await.expect("Reset email");
+    assert!(link.starts_with("/auth/reset/"));
+
+    let res = app.htmx_post("/auth/forgot", &[("email", "nobody@example.com")]).await;
+    assert_eq!(res.status, StatusCode::OK, "unknown emails get the same answer");
+}